@@ -0,0 +1,307 @@
+//! Discovery and loading of the index files exposed by the service.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use actix_web::web::Data;
+use glob::glob;
+use mercator_db::DataBase;
+use serde::Serialize;
+
+use crate::shared_state::SharedState;
+
+/// Size and last modification time of an index file.
+type Stamp = (u64, Option<SystemTime>);
+
+/// Description of a loaded index file.
+#[derive(Clone, Debug, Serialize)]
+pub struct Dataset {
+    path: String,
+    size: u64,
+    modified: Option<u64>, // Seconds since UNIX epoch
+    cores: Vec<String>,
+    load_duration: f64, // Seconds
+}
+
+impl Dataset {
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    /// Cores provided by the index file.
+    pub fn cores(&self) -> &Vec<String> {
+        &self.cores
+    }
+
+    /// Forget the core `name` was provided by the index file, once it has
+    /// been unloaded.
+    pub fn remove_core(&mut self, name: &str) {
+        self.cores.retain(|core| core != name);
+    }
+}
+
+/// Retrieve the canonical paths of the index files found in `data`.
+pub fn list(data: &str) -> Vec<String> {
+    glob(&format!("{}/*.index", data))
+        .expect("Failed to read glob pattern")
+        .filter_map(|entry| match entry {
+            Ok(path) => match path.canonicalize() {
+                Ok(path) => Some(format!("{}", path.display())),
+                Err(_) => None,
+            },
+            Err(_) => None,
+        })
+        .collect::<Vec<_>>()
+}
+
+// Size and last modification time, in seconds since UNIX epoch, of the
+// index file `dataset`, as recorded in its description.
+fn describe(dataset: &str) -> (u64, Option<u64>) {
+    match std::fs::metadata(dataset) {
+        Err(_) => (0, None),
+        Ok(metadata) => (
+            metadata.len(),
+            metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        ),
+    }
+}
+
+fn stamps(datasets: &[String]) -> HashMap<String, Stamp> {
+    datasets
+        .iter()
+        .filter_map(|dataset| match std::fs::metadata(dataset) {
+            Err(_) => None,
+            Ok(metadata) => Some((
+                dataset.clone(),
+                (metadata.len(), metadata.modified().ok()),
+            )),
+        })
+        .collect()
+}
+
+/// Load the index files `datasets` into a new database, and describe the
+/// files actually loaded.
+///
+/// Files which cannot be loaded, or which provide a core already loaded,
+/// are logged and skipped.
+pub fn load(datasets: &[String]) -> (DataBase, Vec<Dataset>) {
+    info_time!("Loading database index");
+
+    let mut spaces = vec![];
+    let mut cores = vec![];
+    let mut loaded = vec![];
+
+    for dataset in datasets {
+        let start = Instant::now();
+        let db = {
+            info_time!("Loading index {}", dataset);
+            DataBase::load(&[dataset.as_str()])
+        };
+        let load_duration = start.elapsed().as_secs_f64();
+
+        let db = match db {
+            Err(e) => {
+                error!("Skipping index {}: {}", dataset, e);
+                continue;
+            }
+            Ok(db) => db,
+        };
+
+        // Reference spaces are shared between index files, keep the first
+        // definition found.
+        for id in db.space_keys() {
+            if let Ok(space) = db.space(id) {
+                if !spaces.iter().any(|s: &mercator_db::space::Space| s.name() == id) {
+                    spaces.push(space.clone());
+                }
+            }
+        }
+
+        let mut provided = vec![];
+        for id in db.core_keys() {
+            if let Ok(core) = db.core(id) {
+                if cores.iter().any(|c: &mercator_db::Core| c.name() == id) {
+                    warn!("Skipping core {} from {}: already loaded", id, dataset);
+                } else {
+                    cores.push(core.clone());
+                    provided.push(id.clone());
+                }
+            }
+        }
+
+        let (size, modified) = describe(dataset);
+
+        loaded.push(Dataset {
+            path: dataset.clone(),
+            size,
+            modified,
+            cores: provided,
+            load_duration,
+        });
+    }
+
+    (DataBase::new(spaces, cores), loaded)
+}
+
+/// Rescan `data`, load the index files added or modified, and replace in
+/// the database of `state` the cores provided by the files which changed.
+///
+/// The cores and reference spaces created through the API, as well as the
+/// cores of the files left untouched, are kept. The changes made through the
+/// API to the cores of the files which changed are lost, this is logged.
+///
+/// The new cores are loaded before taking the write lock, so queries keep
+/// being served from the previous ones in the meantime.
+///
+/// Files which cannot be loaded are skipped until they change again.
+pub fn reload(data: &str, state: &RwLock<SharedState>) -> Vec<Dataset> {
+    // Reloads are triggered by the watcher as well as by the API. The lock
+    // also guards the files which could not be loaded, with their stamp.
+    static FAILED: Mutex<Vec<(String, Stamp)>> = Mutex::new(Vec::new());
+    let mut failed = FAILED
+        .lock()
+        .unwrap_or_else(|e| panic!("Can't acquire lock of the reload: {}", e));
+
+    let files = list(data);
+    let current = state
+        .read()
+        .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e))
+        .datasets()
+        .clone();
+
+    // Files removed or modified since they were loaded are stale.
+    let (unchanged, stale): (Vec<_>, Vec<_>) = current.into_iter().partition(|dataset| {
+        files.contains(&dataset.path)
+            && describe(&dataset.path) == (dataset.size, dataset.modified)
+    });
+    let latest = stamps(&files);
+    let changed = files
+        .into_iter()
+        .filter(|file| !unchanged.iter().any(|dataset| &dataset.path == file))
+        .filter(|file| {
+            !failed
+                .iter()
+                .any(|(path, stamp)| path == file && latest.get(file) == Some(stamp))
+        })
+        .collect::<Vec<_>>();
+
+    if changed.is_empty() && stale.is_empty() {
+        return unchanged;
+    }
+
+    let (db, mut loaded) = load(&changed);
+
+    failed.retain(|(path, stamp)| latest.get(path) == Some(stamp));
+    for file in changed {
+        if !loaded.iter().any(|dataset| dataset.path == file) {
+            if let Some(stamp) = latest.get(&file) {
+                failed.retain(|(path, _)| path != &file);
+                failed.push((file, *stamp));
+            }
+        }
+    }
+
+    let mut context = state
+        .write()
+        .unwrap_or_else(|e| panic!("Can't acquire write lock of the database: {}", e));
+    let current = context.db();
+
+    let stale = stale
+        .iter()
+        .flat_map(|dataset| dataset.cores.iter().cloned())
+        .collect::<HashSet<_>>();
+
+    let mut spaces = current
+        .space_keys()
+        .iter()
+        .filter_map(|id| current.space(id).ok().cloned())
+        .collect::<Vec<_>>();
+    for id in db.space_keys() {
+        if let Ok(space) = db.space(id) {
+            if !spaces.iter().any(|s| s.name() == id) {
+                spaces.push(space.clone());
+            }
+        }
+    }
+
+    let mut cores = current
+        .core_keys()
+        .iter()
+        .filter(|id| !stale.contains(*id))
+        .filter_map(|id| current.core(id).ok().cloned())
+        .collect::<Vec<_>>();
+    for dataset in loaded.iter_mut() {
+        let path = &dataset.path;
+        dataset.cores.retain(|id| match db.core(id) {
+            Err(_) => false,
+            Ok(core) => {
+                if cores.iter().any(|c| c.name() == id) {
+                    warn!("Skipping core {} from {}: already loaded", id, path);
+                    false
+                } else {
+                    cores.push(core.clone());
+                    true
+                }
+            }
+        });
+    }
+
+    let mut datasets = unchanged;
+    datasets.extend(loaded);
+
+    context.replace(DataBase::new(spaces, cores), datasets.clone(), &stale);
+
+    datasets
+}
+
+/// Poll `data` every `interval`, and reload the database of `state` when
+/// index files are added, removed or modified.
+pub fn watch(data: String, interval: Duration, state: Data<RwLock<SharedState>>) {
+    let mut current = stamps(&list(&data));
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+
+        let latest = stamps(&list(&data));
+
+        if latest != current {
+            info!("Index files changed in {}, reloading", data);
+            reload(&data, &state);
+
+            current = latest;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_skips_invalid_files() {
+        let data = std::env::temp_dir().join(format!("mercator-datasets-{}", std::process::id()));
+        std::fs::create_dir_all(&data).unwrap();
+        std::fs::write(data.join("garbage.index"), b"not an index file").unwrap();
+
+        let files = list(&format!("{}", data.display()));
+        assert_eq!(files.len(), 1);
+
+        let (db, loaded) = load(&files);
+        assert!(loaded.is_empty());
+        assert!(db.core_keys().is_empty());
+        assert!(db.space_keys().is_empty());
+
+        std::fs::remove_dir_all(&data).unwrap();
+    }
+}
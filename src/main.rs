@@ -32,16 +32,15 @@
 #[macro_use]
 extern crate measure_time;
 
+mod datasets;
 mod rest_api;
 mod shared_state;
 
 use std::process::exit;
 use std::sync::RwLock;
-
-use glob::glob;
+use std::time::Duration;
 
 use rest_api::Data;
-use rest_api::DataBase;
 use shared_state::SharedState;
 
 /*
@@ -79,6 +78,54 @@ async fn main() -> std::io::Result<()> {
         std::env::set_var("MERCATOR_DATA", ".");
     }
 
+    if std::env::var("MERCATOR_QUERY_THREADS").is_err() {
+        std::env::set_var("MERCATOR_QUERY_THREADS", "0");
+    }
+
+    if std::env::var("MERCATOR_QUERY_TIMEOUT").is_err() {
+        std::env::set_var("MERCATOR_QUERY_TIMEOUT", "0");
+    }
+
+    if std::env::var("MERCATOR_STREAM_THREADS").is_err() {
+        std::env::set_var("MERCATOR_STREAM_THREADS", "16");
+    }
+
+    if std::env::var("MERCATOR_STREAM_TIMEOUT").is_err() {
+        std::env::set_var("MERCATOR_STREAM_TIMEOUT", "30");
+    }
+
+    if std::env::var("MERCATOR_ANONYMOUS_ROLE").is_err() {
+        std::env::set_var("MERCATOR_ANONYMOUS_ROLE", "read");
+    }
+
+    if std::env::var("MERCATOR_MAX_OBJECTS").is_err() {
+        std::env::set_var("MERCATOR_MAX_OBJECTS", "0");
+    }
+
+    if std::env::var("MERCATOR_MAX_POSITIONS").is_err() {
+        std::env::set_var("MERCATOR_MAX_POSITIONS", "0");
+    }
+
+    if std::env::var("MERCATOR_RELOAD_INTERVAL").is_err() {
+        std::env::set_var("MERCATOR_RELOAD_INTERVAL", "10");
+    }
+
+    if std::env::var("MERCATOR_RATE_LIMIT").is_err() {
+        std::env::set_var("MERCATOR_RATE_LIMIT", "0");
+    }
+
+    if std::env::var("MERCATOR_RATE_BURST").is_err() {
+        std::env::set_var("MERCATOR_RATE_BURST", "0");
+    }
+
+    if std::env::var("MERCATOR_AUTH_FAILURES").is_err() {
+        std::env::set_var("MERCATOR_AUTH_FAILURES", "10");
+    }
+
+    if std::env::var("MERCATOR_HEAVY_QUERIES").is_err() {
+        std::env::set_var("MERCATOR_HEAVY_QUERIES", "0");
+    }
+
     let hostname = match std::env::var("MERCATOR_HOST") {
         Ok(val) => val,
         Err(val) => {
@@ -109,32 +156,128 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
-    let datasets = glob(&format!("{}/*.index", data))
-        .expect("Failed to read glob pattern")
-        .filter_map(|entry| match entry {
-            Ok(path) => match path.canonicalize() {
-                Ok(path) => Some(format!("{}", path.display())),
-                Err(_) => None,
-            },
-            Err(_) => None,
-        })
-        .collect::<Vec<_>>();
-
-    let db;
-    // Load a Database:
+    // Only validated here, the query thread pool is created on first use.
+    if let Err(e) = std::env::var("MERCATOR_QUERY_THREADS")
+        .unwrap_or_default()
+        .parse::<usize>()
+    {
+        error!(
+            "Could not convert to usize {} : `{}`",
+            "MERCATOR_QUERY_THREADS", e
+        );
+        exit(1);
+    }
+
+    // Only validated here, the stream thread pool is created on first use.
+    if let Err(e) = std::env::var("MERCATOR_STREAM_THREADS")
+        .unwrap_or_default()
+        .parse::<usize>()
+    {
+        error!(
+            "Could not convert to usize {} : `{}`",
+            "MERCATOR_STREAM_THREADS", e
+        );
+        exit(1);
+    }
+
+    // Only validated here, the timeout is read on first use.
+    match std::env::var("MERCATOR_STREAM_TIMEOUT")
+        .unwrap_or_default()
+        .parse::<u64>()
+    {
+        Ok(v) if v > 0 => (),
+        Ok(v) => {
+            error!("Invalid zero value {} : `{}`", "MERCATOR_STREAM_TIMEOUT", v);
+            exit(1);
+        }
+        Err(e) => {
+            error!(
+                "Could not convert to u64 {} : `{}`",
+                "MERCATOR_STREAM_TIMEOUT", e
+            );
+            exit(1);
+        }
+    }
+
+    // Only validated here, the timeout is read on first use.
+    if let Err(e) = std::env::var("MERCATOR_QUERY_TIMEOUT")
+        .unwrap_or_default()
+        .parse::<u64>()
     {
-        // Load all the index contained in the folder, and fail if anyone of
-        // those is corrupted / incompatible.
-        info_time!("Loading database index");
+        error!(
+            "Could not convert to u64 {} : `{}`",
+            "MERCATOR_QUERY_TIMEOUT", e
+        );
+        exit(1);
+    }
+
+    // Only validated here, the role is read when the server starts.
+    match std::env::var("MERCATOR_ANONYMOUS_ROLE").unwrap_or_default().as_str() {
+        "read" | "write" => (),
+        role => {
+            error!(
+                "Invalid role {} : `{}`, expected read or write",
+                "MERCATOR_ANONYMOUS_ROLE", role
+            );
+            exit(1);
+        }
+    }
+
+    // Only validated here, the size limits are read on first use.
+    for name in &["MERCATOR_MAX_OBJECTS", "MERCATOR_MAX_POSITIONS"] {
+        if let Err(e) = std::env::var(name).unwrap_or_default().parse::<usize>() {
+            error!("Could not convert to usize {} : `{}`", name, e);
+            exit(1);
+        }
+    }
+
+    // Only validated here, the limits are read when the server starts.
+    for name in &["MERCATOR_RATE_LIMIT", "MERCATOR_RATE_BURST"] {
+        match std::env::var(name).unwrap_or_default().parse::<f64>() {
+            Ok(v) if v >= 0.0 => (),
+            Ok(v) => {
+                error!("Invalid negative value {} : `{}`", name, v);
+                exit(1);
+            }
+            Err(e) => {
+                error!("Could not convert to f64 {} : `{}`", name, e);
+                exit(1);
+            }
+        }
+    }
+
+    for name in &["MERCATOR_AUTH_FAILURES", "MERCATOR_HEAVY_QUERIES"] {
+        if let Err(e) = std::env::var(name).unwrap_or_default().parse::<usize>() {
+            error!("Could not convert to usize {} : `{}`", name, e);
+            exit(1);
+        }
+    }
+
+    let reload_interval = match std::env::var("MERCATOR_RELOAD_INTERVAL") {
+        Ok(val) => match val.parse::<u64>() {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Could not convert to u64 {} : `{}`",
+                    "MERCATOR_RELOAD_INTERVAL", e
+                );
+                exit(1);
+            }
+        },
+        Err(val) => {
+            error!("Could not fetch {} : `{}`", "MERCATOR_RELOAD_INTERVAL", val);
+            exit(1);
+        }
+    };
+
+    // Load all the index contained in the folder, skipping the ones which
+    // are corrupted / incompatible.
+    let (db, loaded) = datasets::load(&datasets::list(&data));
+    let state = Data::new(RwLock::new(SharedState::new(db, loaded)));
 
-        db = DataBase::load(&datasets.iter().map(String::as_str).collect::<Vec<_>>())
-            .unwrap_or_else(|e| panic!("Error while loading indices: {}", e));
+    if reload_interval > 0 {
+        datasets::watch(data, Duration::from_secs(reload_interval), state.clone());
     }
 
-    rest_api::run(
-        &hostname,
-        port,
-        Data::new(RwLock::new(SharedState::new(db))),
-    )
-    .await
+    rest_api::run(&hostname, port, state).await
 }
@@ -1,23 +1,48 @@
+use std::sync::Mutex;
 use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
 
+use actix_web::http::header::HeaderName;
+use actix_web::http::header::HeaderValue;
 use serde::Deserialize;
 
-use super::error_422;
+use super::error_422_field;
+use super::error_500;
+use super::error_504;
+use super::export;
 use super::from_properties_by_spaces;
+use super::model;
+use super::ndjson;
 use super::ok_200;
 use super::web;
 use super::web::Data;
 use super::web::Json;
+use super::Access;
+use super::ApiError;
+use super::DataBase;
+use super::Format;
 use super::HandlerResult;
+use super::HttpRequest;
 use super::HttpResponse;
+use super::Page;
 use super::SharedState;
+use super::Shape;
+use crate::shared_state::Cancellation;
+use crate::shared_state::QueryError;
+use crate::shared_state::Timings;
+use crate::shared_state::Truncation;
 use mercator_db::CoreQueryParameters;
 
 #[derive(Debug, Deserialize)]
 pub struct Query {
     query: String,
+    #[serde(alias = "output_space")]
+    space: Option<String>, // Output space, None, means each object in its own original space
     resolution: Option<Vec<u32>>, // None means automatic selection, based on ViewPort
     view_port: Option<(Vec<f64>, Vec<f64>)>,
+    export: Option<Format>,
+    timeout_ms: Option<u64>, // Capped by `MERCATOR_QUERY_TIMEOUT`
 }
 
 impl Query {
@@ -25,57 +50,224 @@ impl Query {
         &self.query
     }
 
+    pub fn space(&self, db: &DataBase) -> Result<&Option<String>, HandlerResult> {
+        if let Some(space_id) = &self.space {
+            if !db.space_keys().contains(space_id) {
+                return Err(error_422_field(
+                    "space",
+                    format!("Invalid reference space id in '{:?}'", self),
+                ));
+            }
+        }
+        Ok(&self.space)
+    }
+
     pub fn resolution(&self) -> &Option<Vec<u32>> {
         &self.resolution
     }
 
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+
     pub fn volume(&self) -> Option<f64> {
-        match &self.view_port {
-            None => None,
-            Some(_view) => None, // FIXME: Need to move the Volume functions from mercator_parser to mercator_db.
-        }
+        self.view_port.as_ref().map(|(low, high)|
+            Shape::BoundingBox(low.into(), high.into()).volume()
+        )
     }
 }
+
 // Also used for the root service.
 pub async fn health() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
-async fn query((parameters, state): (Json<Query>, Data<RwLock<SharedState>>)) -> HandlerResult {
-    trace!("POST '{:?}'", parameters);
-    let context = state
-        .read()
-        .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
-    let query = parameters.query();
-
-    if query.is_empty() {
-        error_422(format!("Invalid query in '{:?}'", query))
-    } else {
-        let parameters = CoreQueryParameters {
-            db: context.db(),
-            output_space: None,
-            threshold_volume: parameters.volume(),
-            view_port: &parameters.view_port,
-            resolution: parameters.resolution(),
-        };
-
-        let results = context
-            .db()
-            .core_keys()
-            .iter()
-            .filter_map(|core| {
-                match context.query(query) {
-                    Err(_) => None, // FIXME: Return errors here instead!!
-                    Ok(tree) => match context.execute(&tree, core, &parameters) {
-                        Err(_) => None, // FIXME: Return errors here instead!!
-                        Ok(objects) => Some(from_properties_by_spaces(objects).collect::<Vec<_>>()),
-                    },
+// Execute the query on all the cores visible to the client, streaming the
+// objects as they are produced.
+fn stream_query(
+    context: &SharedState,
+    access: &Access,
+    parameters: &Query,
+    cancellation: &Cancellation,
+    lines: &ndjson::Lines,
+) -> Result<(), ApiError> {
+    let space = match parameters.space(context.db()) {
+        Err(e) => return e.map(drop),
+        Ok(space) => space,
+    };
+    let core_parameters = CoreQueryParameters {
+        db: context.db(),
+        output_space: space.as_ref().map(String::as_str),
+        threshold_volume: parameters.volume(),
+        view_port: &parameters.view_port,
+        resolution: parameters.resolution(),
+    };
+
+    let tree = context.query(parameters.query(), &mut Timings::default())?;
+
+    // Each core sends its objects as they are produced. Errors reported
+    // before the first line is sent still get the proper status code.
+    let lines = Mutex::new(lines.clone());
+    let sent_by_cores = context.for_each_core(
+        |core| access.allows(core),
+        |core| {
+            let mut lines = lines
+                .lock()
+                .unwrap_or_else(|e| panic!("Can't acquire lock of the stream: {}", e))
+                .clone();
+
+            match context.execute(&tree, core, &core_parameters, cancellation) {
+                Err(e) => Err(QueryError::execution(core, e)),
+                Ok(objects) => {
+                    Ok(from_properties_by_spaces(objects).all(|object| lines.send(&object)))
                 }
-            })
-            .flatten()
-            .collect::<Vec<_>>();
-        ok_200(&results)
+            }
+        },
+    );
+
+    for (_, sent) in sent_by_cores {
+        match sent {
+            Err(e) => return Err(e.into()),
+            Ok(false) => {
+                trace!("Client disconnected, stream aborted");
+                return Ok(());
+            }
+            Ok(true) => (),
+        }
+    }
+
+    // The objects sent so far are only part of the results.
+    if cancellation.is_interrupted() {
+        return error_504().map(drop);
     }
+
+    Ok(())
+}
+
+// Execute the query on all the cores visible to the client, once its output
+// space has been validated. The results are cut to the size limits.
+fn execute_query(
+    context: &SharedState,
+    access: &Access,
+    parameters: &Query,
+    cancellation: &Cancellation,
+    truncation: &Truncation,
+) -> Result<(Vec<model::v2::SpatialObject>, Timings), ApiError> {
+    let core_parameters = CoreQueryParameters {
+        db: context.db(),
+        output_space: parameters.space.as_ref().map(String::as_str),
+        threshold_volume: parameters.volume(),
+        view_port: &parameters.view_port,
+        resolution: parameters.resolution(),
+    };
+
+    let mut timings = Timings::default();
+
+    // Parse and validate once, for all the cores.
+    let tree = context.query(parameters.query(), &mut timings)?;
+
+    // Results are lazily computed, so include their conversion.
+    let start = Instant::now();
+    let objects_by_cores = context.for_each_core(
+        |core| access.allows(core),
+        |core| match context.execute(&tree, core, &core_parameters, cancellation) {
+            Err(e) => Err(QueryError::execution(core, e)),
+            Ok(objects) => Ok(truncation
+                .limit(from_properties_by_spaces(truncation.guard(objects)))
+                .collect::<Vec<_>>()),
+        },
+    );
+    timings.add_execution(start);
+
+    let mut results = vec![];
+    for (_, objects) in objects_by_cores {
+        match objects {
+            Err(e) => return Err(e.into()),
+            Ok(objects) => results.extend(objects),
+        }
+    }
+
+    let results = truncation.limit(results.into_iter()).collect();
+
+    Ok((results, timings))
+}
+
+async fn query(
+    (request, parameters, state): (HttpRequest, Json<Query>, Data<RwLock<SharedState>>),
+) -> HandlerResult {
+    trace!("POST '{:?}'", parameters);
+    if parameters.query().is_empty() {
+        return error_422_field(
+            "query",
+            format!("Invalid query in '{:?}'", parameters.query()),
+        );
+    }
+
+    // Queries are executed outside of the request, which is dropped if the
+    // client goes away.
+    let cancellation = Cancellation::new(parameters.timeout());
+    let access = Access::current();
+    let parameters = parameters.into_inner();
+
+    if ndjson::requested(&request) {
+        if parameters.export.is_some() {
+            return error_422_field("export", "Exports can't be streamed");
+        }
+
+        return ndjson::stream(&request, state, move |context, lines| {
+            stream_query(context, &access, &parameters, &cancellation, lines)
+        })
+        .await;
+    }
+
+    {
+        let context = state
+            .read()
+            .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+        if let Err(e) = parameters.space(context.db()) {
+            return e;
+        }
+    }
+
+    let export = parameters.export;
+    let space = parameters.space.clone();
+    let truncation = Truncation::new();
+    let _cancel = cancellation.on_drop();
+    let execution = {
+        let cancellation = cancellation.clone();
+        let truncation = truncation.clone();
+        web::block(move || {
+            let context = state
+                .read()
+                .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+            execute_query(&context, &access, &parameters, &cancellation, &truncation)
+        })
+        .await
+    };
+
+    let (results, timings) = match execution {
+        Err(e) => return error_500(e),
+        Ok(execution) => execution?,
+    };
+    if cancellation.is_interrupted() {
+        return error_504();
+    }
+
+    let mut response = match export {
+        Some(format) => {
+            let space = space.as_deref();
+            export::render(format, space, &results, truncation.is_truncated())?
+        }
+        None if truncation.is_truncated() => ok_200(&Page::new(results, None, true))?,
+        None => ok_200(&results)?,
+    };
+    if let Ok(value) = HeaderValue::from_str(&timings.to_header()) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("server-timing"), value);
+    }
+
+    Ok(response)
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -111,6 +303,24 @@ mod routing {
         )
         .await;
 
+        expect_200(
+            TestRequest::post().set_json(json!({
+                "query": "json(.,inside(hyperrectangle{[0,0,0],[0,1,1]}))",
+                "view_port": [[0, 0, 0], [0.5, 0.5, 0.5]],
+                "output_space": "std"
+            })),
+            ep,
+        )
+        .await;
+        expect_422(
+            TestRequest::post().set_json(json!({
+                "query": "json(.,inside(hyperrectangle{[0,0,0],[0,1,1]}))",
+                "space": "INVALID"
+            })),
+            ep,
+        )
+        .await;
+
         expect_422(TestRequest::post().set_json(json!({"query": "toto"})), ep).await;
         expect_422(TestRequest::post().set_json(json!({"query": ""})), ep).await;
         expect_400(TestRequest::post().set_json(json!({"invalid": true})), ep).await;
@@ -122,4 +332,73 @@ mod routing {
         expect_405(TestRequest::patch(), ep).await;
         expect_405(TestRequest::delete(), ep).await;
     }
+
+    #[actix_web::test]
+    async fn query_export() {
+        let ep = &get_path("/query");
+        let query = "json(.,inside(hyperrectangle{[0,0,0],[0,1,1]}))";
+
+        for format in &["ply", "obj", "gltf", "csv"] {
+            expect_200(
+                TestRequest::post().set_json(json!({"query": query, "export": format})),
+                ep,
+            )
+            .await;
+        }
+        expect_400(
+            TestRequest::post().set_json(json!({"query": query, "export": "stl"})),
+            ep,
+        )
+        .await;
+        expect_422(
+            TestRequest::post()
+                .insert_header(("Accept", "application/x-ndjson"))
+                .set_json(json!({"query": query, "export": "ply"})),
+            ep,
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn query_timeout() {
+        let ep = &get_path("/query");
+        let query = "json(.,inside(hyperrectangle{[0,0,0],[0,1,1]}))";
+
+        expect_504(
+            TestRequest::post().set_json(json!({"query": query, "timeout_ms": 0})),
+            ep,
+        )
+        .await;
+        expect_504(
+            TestRequest::post()
+                .insert_header(("Accept", "application/x-ndjson"))
+                .set_json(json!({"query": query, "timeout_ms": 0})),
+            ep,
+        )
+        .await;
+        expect_200(
+            TestRequest::post().set_json(json!({"query": query, "timeout_ms": 60000})),
+            ep,
+        )
+        .await;
+        expect_422(
+            TestRequest::post().set_json(json!({"query": "toto", "timeout_ms": 0})),
+            ep,
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn query_stream() {
+        let ep = &get_path("/query");
+        let stream = &format!("{}?stream=true", ep);
+        let ndjson = || TestRequest::post().insert_header(("Accept", "application/x-ndjson"));
+        let query = json!({"query": "json(.,inside(hyperrectangle{[0,0,0],[0,1,1]}))"});
+
+        expect_200(ndjson().set_json(&query), ep).await;
+        expect_200(TestRequest::post().set_json(&query), stream).await;
+
+        expect_422(ndjson().set_json(json!({"query": "toto"})), ep).await;
+        expect_422(ndjson().set_json(json!({"query": ""})), ep).await;
+    }
 }
@@ -0,0 +1,97 @@
+use std::sync::RwLock;
+
+use super::error_500;
+use super::ok_200;
+use super::web;
+use super::web::Data;
+use super::Access;
+use super::HandlerResult;
+use super::Role;
+use super::SharedState;
+use crate::datasets;
+
+async fn reload(state: Data<RwLock<SharedState>>) -> HandlerResult {
+    trace!("POST reload");
+    Access::current().require(Role::Admin)?;
+
+    let data = std::env::var("MERCATOR_DATA").unwrap_or_else(|_| ".".to_string());
+
+    // Loading the changed files may take a while, outside of the HTTP workers.
+    match web::block(move || datasets::reload(&data, &state)).await {
+        Err(e) => error_500(e),
+        Ok(datasets) => ok_200(&datasets),
+    }
+}
+
+async fn list(state: Data<RwLock<SharedState>>) -> HandlerResult {
+    trace!("GET datasets");
+    Access::current().require(Role::Admin)?;
+
+    let context = state
+        .read()
+        .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+
+    ok_200(context.datasets())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/admin/reload").route(web::post().to(reload)));
+    cfg.service(web::resource("/admin/datasets").route(web::get().to(list)));
+}
+
+#[cfg(test)]
+mod routing {
+    use crate::rest_api::tests_utils::*;
+    use crate::rest_api::Role;
+    use crate::rest_api::Tokens;
+    use actix_web::http::StatusCode;
+
+    // Send `requests`, and retrieve the status codes of the responses.
+    async fn statuses(requests: Vec<TestRequest>) -> Vec<StatusCode> {
+        let tokens = Tokens::anonymous(Role::Write).with_admin(Some("admin-test".to_string()));
+
+        call(tokens, None, requests)
+            .await
+            .into_iter()
+            .map(|reply| reply.status)
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn reload() {
+        let ep = &get_path("/admin/reload");
+
+        let statuses = statuses(vec![
+            TestRequest::post().uri(ep),
+            TestRequest::post()
+                .uri(ep)
+                .insert_header(("Authorization", "Bearer invalid")),
+        ])
+        .await;
+        assert_eq!(statuses, vec![StatusCode::UNAUTHORIZED; 2]);
+
+        expect_405(TestRequest::get(), ep).await;
+        expect_405(TestRequest::put(), ep).await;
+        expect_405(TestRequest::patch(), ep).await;
+        expect_405(TestRequest::delete(), ep).await;
+    }
+
+    #[actix_web::test]
+    async fn datasets() {
+        let ep = &get_path("/admin/datasets");
+
+        let statuses = statuses(vec![
+            TestRequest::get()
+                .uri(ep)
+                .insert_header(("Authorization", "Bearer admin-test")),
+            TestRequest::get().uri(ep),
+        ])
+        .await;
+        assert_eq!(statuses, vec![StatusCode::OK, StatusCode::UNAUTHORIZED]);
+
+        expect_405(TestRequest::post(), ep).await;
+        expect_405(TestRequest::put(), ep).await;
+        expect_405(TestRequest::patch(), ep).await;
+        expect_405(TestRequest::delete(), ep).await;
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use mercator_db::CoreQueryParameters;
+use mercator_db::IterObjects;
+use mercator_db::IterObjectsBySpaces;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::Filters;
+use super::SharedState;
+
+// Number of bins per axis of the histograms.
+const HISTOGRAM_BINS: usize = 16;
+
+/// Statistics to compute on the matching objects, instead of returning them.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Count,
+    Bbox,
+    Histogram,
+}
+
+/// Matches within one reference space.
+#[derive(Clone, Debug, Serialize)]
+pub struct SpaceAggregate {
+    space: String,
+    objects: usize,
+    positions: usize,
+    bounding_box: Option<(Vec<f64>, Vec<f64>)>,
+    // Positions per bin, for each axis. Bins evenly split the box of the
+    // space given to `CoreAggregate::new`.
+    histogram: Option<Vec<Vec<usize>>>,
+}
+
+impl SpaceAggregate {
+    fn new(space: &str) -> Self {
+        SpaceAggregate {
+            space: space.to_string(),
+            objects: 0,
+            positions: 0,
+            bounding_box: None,
+            histogram: None,
+        }
+    }
+
+    fn extend(&mut self, low: &[f64], high: &[f64]) {
+        match &mut self.bounding_box {
+            None => self.bounding_box = Some((low.to_vec(), high.to_vec())),
+            Some((l, h)) => {
+                for (k, v) in low.iter().enumerate() {
+                    l[k] = l[k].min(*v);
+                }
+                for (k, v) in high.iter().enumerate() {
+                    h[k] = h[k].max(*v);
+                }
+            }
+        }
+    }
+
+    // Histograms of a space all split the same box, so they are added up.
+    fn merge(&mut self, other: &SpaceAggregate) {
+        self.objects += other.objects;
+        self.positions += other.positions;
+        if let Some((low, high)) = &other.bounding_box {
+            self.extend(low, high);
+        }
+        if let (Some(total), Some(other)) = (&mut self.histogram, &other.histogram) {
+            for (bins, other) in total.iter_mut().zip(other) {
+                for (bin, count) in bins.iter_mut().zip(other) {
+                    *bin += count;
+                }
+            }
+        }
+    }
+}
+
+// Count `position` in the bins of `histogram`, which evenly split `low` to
+// `high` along each axis. Positions outside are ignored.
+fn bin(histogram: &mut [Vec<usize>], (low, high): &(Vec<f64>, Vec<f64>), position: &[f64]) {
+    let axes = low.iter().zip(high).zip(position);
+    if axes.clone().any(|((low, high), value)| value < low || value > high) {
+        return;
+    }
+
+    for (bins, ((low, high), value)) in histogram.iter_mut().zip(axes) {
+        let width = high - low;
+        let bin = if width > 0.0 {
+            ((value - low) / width * HISTOGRAM_BINS as f64) as usize
+        } else {
+            0
+        };
+        // The higher bound belongs to the last bin.
+        bins[bin.min(HISTOGRAM_BINS - 1)] += 1;
+    }
+}
+
+/// Boxes split by the histograms, per reference space: the view port of
+/// `parameters` for its output space, the graduated range of the axes
+/// otherwise.
+pub fn histogram_boxes(
+    context: &SharedState,
+    parameters: &Filters,
+) -> HashMap<String, (Vec<f64>, Vec<f64>)> {
+    let mut boxes = context
+        .graduations()
+        .into_iter()
+        .map(|(space, graduations)| (space, graduations.bounds()))
+        .collect::<HashMap<_, _>>();
+
+    if let (Some(space), Some(view_port)) = (&parameters.space, &parameters.view_port) {
+        boxes.insert(space.clone(), view_port.clone());
+    }
+
+    boxes
+}
+
+/// Matches within one core.
+#[derive(Clone, Debug, Serialize)]
+pub struct CoreAggregate {
+    core: String,
+    objects: usize,
+    positions: usize,
+    spaces: Vec<SpaceAggregate>,
+}
+
+impl CoreAggregate {
+    /// Consume `objects_by_spaces`, keeping only the statistics requested.
+    ///
+    /// Histograms split the box of each space in `boxes`, see
+    /// `histogram_boxes`. None is computed for the spaces missing from it.
+    pub fn new(
+        core: &str,
+        objects_by_spaces: IterObjectsBySpaces,
+        aggregation: Aggregation,
+        boxes: &HashMap<String, (Vec<f64>, Vec<f64>)>,
+    ) -> Self {
+        let mut ids = HashSet::new();
+        let mut by_spaces = HashMap::new();
+
+        for (space, objects) in objects_by_spaces {
+            let (aggregate, space_ids) = by_spaces
+                .entry(space.to_string())
+                .or_insert_with(|| (SpaceAggregate::new(space), HashSet::new()));
+
+            let bounds = match aggregation {
+                Aggregation::Histogram => boxes.get(space),
+                _ => None,
+            };
+            if let (Some((low, _)), None) = (bounds, &aggregate.histogram) {
+                aggregate.histogram = Some(vec![vec![0; HISTOGRAM_BINS]; low.len()]);
+            }
+
+            for (position, properties) in objects {
+                ids.insert(properties.id());
+                space_ids.insert(properties.id());
+                aggregate.positions += 1;
+
+                if aggregation != Aggregation::Count {
+                    let position: Vec<f64> = position.into();
+                    aggregate.extend(&position, &position);
+
+                    if let (Some(histogram), Some(bounds)) = (&mut aggregate.histogram, bounds) {
+                        bin(histogram, bounds, &position);
+                    }
+                }
+            }
+        }
+
+        let mut spaces = by_spaces
+            .drain()
+            .map(|(_, (mut aggregate, space_ids))| {
+                aggregate.objects = space_ids.len();
+                aggregate
+            })
+            .collect::<Vec<_>>();
+        spaces.sort_by(|a, b| a.space.cmp(&b.space));
+
+        CoreAggregate {
+            core: core.to_string(),
+            objects: ids.len(),
+            positions: spaces.iter().map(|space| space.positions).sum(),
+            spaces,
+        }
+    }
+}
+
+/// Matches per core, and overall per reference space.
+#[derive(Clone, Debug, Serialize)]
+pub struct Aggregate {
+    cores: Vec<CoreAggregate>,
+    spaces: Vec<SpaceAggregate>,
+}
+
+impl Aggregate {
+    pub fn new(cores: Vec<CoreAggregate>) -> Self {
+        let mut by_spaces: HashMap<&str, SpaceAggregate> = HashMap::new();
+        for space in cores.iter().flat_map(|core| &core.spaces) {
+            by_spaces
+                .entry(space.space.as_str())
+                .and_modify(|total| total.merge(space))
+                .or_insert_with(|| space.clone());
+        }
+
+        let mut spaces = by_spaces.drain().map(|(_, v)| v).collect::<Vec<_>>();
+        spaces.sort_by(|a, b| a.space.cmp(&b.space));
+
+        Aggregate { cores, spaces }
+    }
+}
+
+/// Positions of all the objects of `core`, as a filter matching everything
+/// would return them.
+pub fn everything<'a>(
+    core: &'a mercator_db::Core,
+    parameters: &'a CoreQueryParameters<'a>,
+) -> IterObjectsBySpaces<'a> {
+    let mut objects_by_spaces: IterObjectsBySpaces = vec![];
+
+    for properties in core.keys() {
+        match core.get_by_id(parameters, properties.id()) {
+            Err(_) => (), // FIXME: Return error ?
+            Ok(positions_by_spaces) => {
+                for (space, positions) in positions_by_spaces {
+                    let objects: IterObjects =
+                        Box::new(positions.map(move |position| (position, properties)));
+                    objects_by_spaces.push((space, objects));
+                }
+            }
+        }
+    }
+
+    objects_by_spaces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms() {
+        let bounds = (vec![0.0, 0.0], vec![1.0, 0.0]);
+        let mut histogram = vec![vec![0; HISTOGRAM_BINS]; 2];
+
+        bin(&mut histogram, &bounds, &[0.5, 0.0]);
+        bin(&mut histogram, &bounds, &[1.0, 0.0]);
+        bin(&mut histogram, &bounds, &[-1.0, 3.0]);
+        bin(&mut histogram, &bounds, &[2.0, 0.0]);
+        bin(&mut histogram, &bounds, &[0.5, 1.0]);
+        assert_eq!(histogram[0][0], 0);
+        assert_eq!(histogram[0][HISTOGRAM_BINS / 2], 1);
+        assert_eq!(histogram[0][HISTOGRAM_BINS - 1], 1);
+        assert_eq!(histogram[1][0], 2);
+
+        let mut total = SpaceAggregate::new("space");
+        total.histogram = Some(histogram.clone());
+        let mut other = SpaceAggregate::new("space");
+        other.histogram = Some(histogram);
+        total.merge(&other);
+        assert_eq!(total.histogram.unwrap()[1][0], 4);
+    }
+}
@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use actix_web::dev::ServiceRequest;
+use mercator_db::Core;
+use serde::Deserialize;
+
+use super::error_401;
+use super::error_403;
+use super::http;
+use super::web::Data;
+use super::ApiError;
+use super::DataBase;
+use super::ErrorCode;
+
+tokio::task_local! {
+    // Permissions of the client of the request being handled.
+    static ACCESS: Access;
+}
+
+/// Permissions granted to a token, each role includes the previous ones.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,  // GET requests, and POST requests filtering data
+    Write, // PUT, PATCH and DELETE requests
+    Admin, // Administration endpoints
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "read" => Ok(Role::Read),
+            "write" => Ok(Role::Write),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role '{}'", role)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Read => write!(f, "read"),
+            Role::Write => write!(f, "write"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Permissions of a client. Cores it may not see are handled as if they did
+/// not exist.
+#[derive(Clone, Debug)]
+pub struct Access {
+    client: Option<Arc<str>>, // None for anonymous requests
+    request: Arc<str>,        // Method and path, for the audit log
+    role: Role,
+    cores: Option<Arc<HashSet<String>>>, // None grants access to all the cores
+}
+
+impl Access {
+    // Anonymous access to the public endpoints only.
+    fn public(request: &str) -> Self {
+        Access {
+            client: None,
+            request: request.into(),
+            role: Role::Read,
+            cores: Some(Arc::new(HashSet::new())),
+        }
+    }
+
+    /// Permissions granted to the current request. Code running outside of
+    /// a request, for example on another thread, sees no core at all.
+    pub fn current() -> Self {
+        ACCESS
+            .try_with(Clone::clone)
+            .unwrap_or_else(|_| Access::public(""))
+    }
+
+    /// Handle the request of `future` with `self` as the permissions.
+    pub fn scope<F>(self, future: F) -> impl Future<Output = F::Output>
+    where
+        F: Future,
+    {
+        ACCESS.scope(self, future)
+    }
+
+    /// Name of the token used, None for anonymous requests.
+    pub fn client(&self) -> Option<&str> {
+        self.client.as_deref()
+    }
+
+    pub fn allows(&self, core: &str) -> bool {
+        match &self.cores {
+            None => true,
+            Some(cores) => cores.contains(core),
+        }
+    }
+
+    /// Retrieve `core` from `db`, if visible.
+    pub fn core<'d>(&self, db: &'d DataBase, core: &str) -> Result<&'d Core, String> {
+        if self.allows(core) {
+            db.core(core)
+        } else {
+            Err(format!("Unknown core '{}'", core))
+        }
+    }
+
+    /// Check the client holds `role`, denials are logged for auditing.
+    pub fn require(&self, role: Role) -> Result<(), ApiError> {
+        if self.role >= role {
+            return Ok(());
+        }
+
+        match &self.client {
+            None => {
+                warn!(
+                    target: "audit",
+                    "Denied '{}' to anonymous client: {} role required",
+                    self.request, role
+                );
+                error_401().map(drop)
+            }
+            Some(client) => {
+                warn!(
+                    target: "audit",
+                    "Denied '{}' to '{}': {} role required",
+                    self.request, client, role
+                );
+                error_403().map(drop)
+            }
+        }
+    }
+}
+
+// Entry of the tokens file.
+#[derive(Debug, Deserialize)]
+struct Token {
+    name: String,
+    token: String,
+    role: Option<Role>,         // None grants the read role only
+    cores: Option<Vec<String>>, // None grants access to all the cores
+}
+
+// Permissions attached to a token.
+#[derive(Debug)]
+struct Grant {
+    name: Arc<str>,
+    role: Role,
+    cores: Option<Arc<HashSet<String>>>,
+}
+
+/// Accepted credentials, loaded from the JSON file set in `MERCATOR_TOKENS`:
+///
+/// ```json
+/// [
+///     {"name": "atlas", "token": "...", "cores": ["10k"]},
+///     {"name": "curation", "token": "...", "role": "write"}
+/// ]
+/// ```
+///
+/// Authentication is disabled when `MERCATOR_TOKENS` is not set, anonymous
+/// clients are then granted the role set in `MERCATOR_ANONYMOUS_ROLE` on all
+/// the cores, read only by default.
+///
+/// The administration token, set in `MERCATOR_ADMIN_TOKEN`, is accepted in
+/// both cases.
+#[derive(Debug)]
+pub struct Tokens {
+    tokens: Option<HashMap<String, Grant>>,
+    anonymous: Role, // Only used when authentication is disabled
+    admin: Option<String>,
+}
+
+impl Default for Tokens {
+    fn default() -> Self {
+        Tokens::anonymous(Role::Read)
+    }
+}
+
+impl Tokens {
+    /// Disable authentication, granting `role` to anonymous clients.
+    pub fn anonymous(role: Role) -> Self {
+        Tokens {
+            tokens: None,
+            anonymous: role,
+            admin: None,
+        }
+    }
+
+    /// Accept `token` as the administration token, always granted the admin
+    /// role.
+    pub fn with_admin(self, token: Option<String>) -> Self {
+        Tokens {
+            admin: token.filter(|token| !token.is_empty()),
+            ..self
+        }
+    }
+
+    pub fn load(file: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?;
+        let entries = serde_json::from_str::<Vec<Token>>(&content)
+            .map_err(|e| format!("{}: {}", file, e))?;
+
+        let mut tokens = HashMap::new();
+        for entry in entries {
+            if entry.token.is_empty() {
+                return Err(format!("{}: empty token for '{}'", file, entry.name));
+            }
+
+            let grant = Grant {
+                name: entry.name.as_str().into(),
+                role: entry.role.unwrap_or(Role::Read),
+                cores: entry
+                    .cores
+                    .map(|cores| Arc::new(cores.into_iter().collect())),
+            };
+            if tokens.insert(entry.token, grant).is_some() {
+                return Err(format!("{}: duplicate token for '{}'", file, entry.name));
+            }
+        }
+
+        Ok(Tokens {
+            tokens: Some(tokens),
+            anonymous: Role::Read,
+            admin: None,
+        })
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let tokens = match std::env::var("MERCATOR_TOKENS") {
+            Ok(file) if !file.is_empty() => Tokens::load(&file)?,
+            _ => match std::env::var("MERCATOR_ANONYMOUS_ROLE") {
+                Ok(role) if !role.is_empty() => match role.parse()? {
+                    Role::Admin => {
+                        return Err("Anonymous clients can't be granted the admin role".into())
+                    }
+                    role => Tokens::anonymous(role),
+                },
+                _ => Tokens::default(),
+            },
+        };
+
+        Ok(tokens.with_admin(std::env::var("MERCATOR_ADMIN_TOKEN").ok()))
+    }
+}
+
+// Credentials, either as a bearer token or as an API key.
+fn credentials(request: &ServiceRequest) -> Option<&str> {
+    let headers = request.headers();
+
+    if let Some(value) = headers.get(http::header::AUTHORIZATION) {
+        return value.to_str().ok()?.strip_prefix("Bearer ");
+    }
+
+    headers.get("x-api-key")?.to_str().ok()
+}
+
+/// Health checks, documentation and static files do not require credentials.
+pub fn public(path: &str) -> bool {
+    let prefix = std::env::var("MERCATOR_BASE").unwrap_or_default();
+    let path = path.strip_prefix(prefix.as_str()).unwrap_or(path);
+    let path = path.strip_prefix("/v1").unwrap_or(path);
+
+    path == "/health" || path.starts_with("/static/") || path.starts_with("/api/")
+}
+
+/// Permissions granted to `request`, according to the tokens registered as
+/// application data, including the administration token.
+///
+/// Requests to endpoints which are not public need credentials, which grant
+/// at least the read role. Other roles are checked by the handlers. Without
+/// tokens registered, anonymous clients may only read.
+pub fn authenticate(request: &ServiceRequest) -> Result<Access, ApiError> {
+    let description = format!("{} {}", request.method(), request.path());
+    let presented = credentials(request);
+
+    let registered = request.app_data::<Data<Tokens>>();
+
+    let admin = registered.and_then(|tokens| tokens.admin.as_deref());
+    if presented.is_some() && presented == admin {
+        return Ok(Access {
+            client: Some("admin".into()),
+            request: description.into(),
+            role: Role::Admin,
+            cores: None,
+        });
+    }
+
+    let (tokens, anonymous) = match registered {
+        Some(tokens) => (tokens.tokens.as_ref(), tokens.anonymous),
+        None => (None, Role::Read),
+    };
+    let tokens = match tokens {
+        Some(tokens) => tokens,
+        None => {
+            return Ok(Access {
+                client: None,
+                request: description.into(),
+                role: anonymous,
+                cores: None,
+            })
+        }
+    };
+
+    match presented.and_then(|token| tokens.get(token)) {
+        Some(grant) => {
+            trace!("Authenticated '{}'", grant.name);
+            Ok(Access {
+                client: Some(grant.name.clone()),
+                request: description.into(),
+                role: grant.role,
+                cores: grant.cores.clone(),
+            })
+        }
+        None if public(request.path()) => Ok(Access::public(&description)),
+        None => Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            "Missing or invalid credentials",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load() {
+        let file = std::env::temp_dir().join("mercator-tokens-duplicate.json");
+        std::fs::write(&file, r#"[{"name": "a", "token": "x"}, {"name": "b", "token": "x"}]"#)
+            .unwrap();
+
+        assert!(Tokens::load(file.to_str().unwrap()).is_err());
+        assert!(Tokens::load("/does/not/exist.json").is_err());
+    }
+}
+
+#[cfg(test)]
+mod routing {
+    use super::super::tests_utils::*;
+    use super::Role;
+    use super::Tokens;
+    use actix_web::http::header;
+    use actix_web::http::StatusCode;
+    use serde_json::json;
+
+    const TOKENS: &str = r#"[
+        {"name": "reader", "token": "secret-read"},
+        {"name": "writer", "token": "secret-write", "role": "write"},
+        {"name": "none", "token": "secret-none", "cores": []}
+    ]"#;
+
+    /// Checks status code of `path` when authenticated with `token`.
+    async fn expect_code(
+        method: TestRequest,
+        path: &str,
+        token: Option<&str>,
+        code: StatusCode,
+    ) {
+        let file = std::env::temp_dir().join(format!("mercator-tokens-{}.json", code));
+        std::fs::write(&file, TOKENS).unwrap();
+        let tokens = Tokens::load(file.to_str().unwrap()).unwrap();
+
+        let method = match token {
+            None => method,
+            Some(token) => method.insert_header(("X-API-Key", token)),
+        };
+        let reply = call(tokens, None, vec![method.uri(path)]).await.remove(0);
+        assert_eq!(reply.status, code);
+    }
+
+    #[actix_web::test]
+    async fn anonymous() {
+        let status = |tokens: Tokens, method: TestRequest, path: String| async move {
+            call(tokens, None, vec![method.uri(&path)]).await.remove(0).status
+        };
+        let delete = || TestRequest::delete().set_json(json!(["INVALID"]));
+
+        // Read only by default.
+        assert_eq!(
+            status(Tokens::default(), TestRequest::get(), get_core(CORE)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Tokens::default(), delete(), get_objects("")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(Tokens::anonymous(Role::Write), delete(), get_objects("")).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn credentials() {
+        let ep = &get_core(CORE);
+
+        expect_code(TestRequest::get(), ep, Some("secret-read"), StatusCode::OK).await;
+        expect_code(
+            TestRequest::get().insert_header((header::AUTHORIZATION, "Bearer secret-read")),
+            ep,
+            None,
+            StatusCode::OK,
+        )
+        .await;
+        expect_code(TestRequest::get(), ep, None, StatusCode::UNAUTHORIZED).await;
+        expect_code(TestRequest::get(), ep, Some("invalid"), StatusCode::UNAUTHORIZED).await;
+    }
+
+    #[actix_web::test]
+    async fn public() {
+        expect_code(TestRequest::get(), &get_path("/health"), None, StatusCode::OK).await;
+        expect_code(TestRequest::get(), "/health", None, StatusCode::OK).await;
+    }
+
+    #[actix_web::test]
+    async fn roles() {
+        let (read, write) = (Some("secret-read"), Some("secret-write"));
+        let objects = &get_objects("");
+        let delete = || TestRequest::delete().set_json(json!(["INVALID"]));
+
+        expect_code(delete(), objects, read, StatusCode::FORBIDDEN).await;
+        expect_code(delete(), objects, write, StatusCode::NOT_FOUND).await;
+        expect_code(
+            TestRequest::post().set_json(json!({})),
+            objects,
+            read,
+            StatusCode::OK,
+        )
+        .await;
+
+        let datasets = &get_path("/admin/datasets");
+        expect_code(TestRequest::get(), datasets, write, StatusCode::FORBIDDEN).await;
+    }
+
+    #[actix_web::test]
+    async fn space_roles() {
+        let read = Some("secret-read");
+        let space = &get_space(SPACE);
+        let axis = |unit_vector| {
+            json!({
+                "measurement_unit": "m",
+                "graduation": {"set": "R", "minimum": 0.0, "maximum": 1.0, "steps": 1000},
+                "unit_vector": unit_vector
+            })
+        };
+        let definition = json!({
+            "name": SPACE.trim_start_matches('/'),
+            "origin": [0.0, 0.0, 0.0],
+            "axes": [axis([1.0, 0.0, 0.0]), axis([0.0, 1.0, 0.0]), axis([0.0, 0.0, 1.0])]
+        });
+
+        expect_code(
+            TestRequest::put().set_json(&definition),
+            space,
+            read,
+            StatusCode::FORBIDDEN,
+        )
+        .await;
+        expect_code(
+            TestRequest::patch().set_json(json!({"origin": [1.0, 0.0, 0.0]})),
+            space,
+            read,
+            StatusCode::FORBIDDEN,
+        )
+        .await;
+        expect_code(TestRequest::delete(), space, read, StatusCode::FORBIDDEN).await;
+        expect_code(TestRequest::get(), space, read, StatusCode::OK).await;
+    }
+
+    #[actix_web::test]
+    async fn restricted() {
+        let none = Some("secret-none");
+
+        expect_code(TestRequest::get(), &get_core(CORE), none, StatusCode::NOT_FOUND).await;
+        expect_code(
+            TestRequest::post().set_json(json!({})),
+            &get_objects(""),
+            none,
+            StatusCode::NOT_FOUND,
+        )
+        .await;
+        expect_code(
+            TestRequest::post().set_json(json!({
+                "query": "json(.,inside(hyperrectangle{[0,0,0],[0,1,1]}))"
+            })),
+            &get_path("/query"),
+            none,
+            StatusCode::OK,
+        )
+        .await;
+    }
+}
@@ -1,18 +1,116 @@
 use std::sync::RwLock;
 
+use serde::Deserialize;
+
 use super::error_400;
 use super::error_404;
+use super::error_409;
+use super::error_422_field;
+use super::error_500;
 use super::ok_200;
+use super::spatial_objects::MODIFY_ATTEMPTS;
 use super::web;
 use super::web::Data;
+use super::web::Json;
 use super::web::Path;
+use super::web::Query;
+use super::Access;
+use super::ApiError;
 use super::Core;
 use super::HandlerResult;
+use super::Role;
 use super::SharedState;
 
-async fn put(path: Path<String>) -> HandlerResult {
-    trace!("PUT Triggered on {}", path);
-    error_400()
+#[derive(Debug, Deserialize)]
+pub struct DeleteParameters {
+    remove_file: Option<bool>,
+}
+
+impl DeleteParameters {
+    pub fn remove_file(&self) -> bool {
+        self.remove_file.unwrap_or(false)
+    }
+}
+
+// Describe the core `name`, once it has been installed.
+fn describe(state: &RwLock<SharedState>, name: &str) -> Result<Core, ApiError> {
+    let context = state
+        .read()
+        .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+
+    match context.db().core(name) {
+        Err(e) => error_500(e).map(|_| unreachable!()),
+        Ok(core) => match Core::describe(&context, core) {
+            Err(e) => error_500(e).map(|_| unreachable!()),
+            Ok(core) => Ok(core),
+        },
+    }
+}
+
+async fn put(
+    (path, core, state): (Path<String>, Json<Core>, Data<RwLock<SharedState>>),
+) -> HandlerResult {
+    trace!("PUT '{:?}'", path);
+    Access::current().require(Role::Write)?;
+    let name = path.to_string();
+
+    if core.name() != &name {
+        return error_422_field(
+            "name",
+            format!(
+                "Core name '{}' does not match '{}'",
+                core.name(),
+                name
+            ),
+        );
+    }
+
+    // Cores outside of the allow-list of the client do not exist for it.
+    if !Access::current().allows(&name) {
+        return error_404();
+    }
+
+    let scales = match core.scales() {
+        Err(e) => return error_422_field("scales", e),
+        Ok(scales) => scales,
+    };
+
+    let version = core.version().clone();
+    let created = web::block(move || {
+        for _ in 0..MODIFY_ATTEMPTS {
+            let job = state
+                .read()
+                .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e))
+                .create_job(&name, &version, scales.clone());
+            let job = match job {
+                Err(e) => return error_500(e).map(|_| unreachable!()),
+                Ok(job) => job,
+            };
+
+            // The current index keeps serving queries until the new one is
+            // ready.
+            let core = job.build();
+
+            let installed = state
+                .write()
+                .unwrap_or_else(|e| panic!("Can't acquire write lock of the database: {}", e))
+                .install(job, core);
+
+            match installed {
+                Err(e) => return error_500(e).map(|_| unreachable!()),
+                Ok(true) => return describe(&state, &name),
+                Ok(false) => trace!("Core '{}' modified concurrently, retrying", name),
+            }
+        }
+
+        error_409().map(|_| unreachable!())
+    })
+    .await;
+
+    match created {
+        Err(e) => error_500(e),
+        Ok(core) => ok_200(&core?),
+    }
 }
 
 async fn get((core, state): (Path<String>, Data<RwLock<SharedState>>)) -> HandlerResult {
@@ -22,8 +120,11 @@ async fn get((core, state): (Path<String>, Data<RwLock<SharedState>>)) -> Handle
         .read()
         .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
 
-    match context.db().core(&core) {
-        Ok(core) => ok_200(&Core::from(core)),
+    match Access::current().core(context.db(), &core) {
+        Ok(core) => match Core::describe(&context, core) {
+            Err(e) => error_500(e),
+            Ok(core) => ok_200(&core),
+        },
         Err(_) => error_404(),
     }
 }
@@ -33,9 +134,61 @@ async fn patch(path: Path<String>) -> HandlerResult {
     error_400()
 }
 
-async fn delete(path: Path<String>) -> HandlerResult {
-    trace!("DELETE Triggered on {}", path);
-    error_400()
+async fn delete(
+    (path, parameters, state): (Path<String>, Query<DeleteParameters>, Data<RwLock<SharedState>>),
+) -> HandlerResult {
+    trace!("DELETE '{:?}', {:?}", path, parameters);
+    Access::current().require(Role::Write)?;
+    if parameters.remove_file() {
+        // Removing files from the data folder is an administrative task.
+        Access::current().require(Role::Admin)?;
+    }
+    let name = path.to_string();
+    let mut context = state
+        .write()
+        .unwrap_or_else(|e| panic!("Can't acquire write lock of the database: {}", e));
+
+    // Summarizing the content of the core would scan it under the lock.
+    let core = match Access::current().core(context.db(), &name) {
+        Err(_) => return error_404(),
+        Ok(core) => Core::identify(&context, core),
+    };
+
+    // The index file the core was loaded from, if any.
+    let file = match context
+        .datasets()
+        .iter()
+        .find(|dataset| dataset.cores().contains(&name))
+    {
+        None => None,
+        Some(dataset) if parameters.remove_file() && dataset.cores().len() > 1 => {
+            trace!("Index file {} holds other cores", dataset.path());
+            return error_409();
+        }
+        Some(dataset) => Some(dataset.path().clone()),
+    };
+
+    if let Err(e) = context.drop_core(&name) {
+        return error_500(e);
+    }
+
+    if parameters.remove_file() {
+        match file {
+            None => warn!("Core {} was not loaded from an index file", name),
+            Some(file) => {
+                match std::fs::remove_file(&file) {
+                    Ok(()) => info!("Removed index file {}", file),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        warn!("No index file {} to remove", file)
+                    }
+                    Err(e) => return error_500(e),
+                }
+                context.drop_dataset(&file);
+            }
+        }
+    }
+
+    ok_200(&core)
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -51,6 +204,7 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 #[cfg(test)]
 mod routing {
     use super::super::tests_utils::*;
+    use serde_json::json;
 
     const INSTANCE_EXISTS: &str = CORE;
     const INSTANCE_INVALID: &str = "/41-doesnotexists";
@@ -64,6 +218,34 @@ mod routing {
         json::expect_200(TestRequest::put(), &get_core(INSTANCE_INVALID), "".to_string()).await;
     }
 
+    #[actix_web::test]
+    async fn put_core() {
+        let core = |name: &str, scales| json!({"name": name, "version": "1", "scales": scales});
+        let new = INSTANCE_INVALID.trim_start_matches('/');
+        let existing = INSTANCE_EXISTS.trim_start_matches('/');
+
+        expect_200(
+            TestRequest::put().set_json(core(new, json!([]))),
+            &get_core(INSTANCE_INVALID),
+        )
+        .await;
+        expect_200(
+            TestRequest::put().set_json(core(existing, json!([[0, 0, 0], [1, 1, 1]]))),
+            &get_core(INSTANCE_EXISTS),
+        )
+        .await;
+        expect_422(
+            TestRequest::put().set_json(core(existing, json!([]))),
+            &get_core(INSTANCE_INVALID),
+        )
+        .await;
+        expect_422(
+            TestRequest::put().set_json(core(new, json!([[-1, 0, 0]]))),
+            &get_core(INSTANCE_INVALID),
+        )
+        .await;
+    }
+
     #[actix_web::test]
     async fn patch() {
         json::expect_200(TestRequest::patch(), &get_core(INSTANCE_EXISTS), "".to_string()).await;
@@ -81,6 +263,11 @@ mod routing {
     async fn delete() {
         expect_200(TestRequest::delete(), &get_core(INSTANCE_EXISTS)).await;
         expect_404(TestRequest::delete(), &get_core(INSTANCE_INVALID)).await;
+
+        // Removing the index file requires the admin role, which anonymous
+        // clients can't be granted.
+        let remove_file = format!("{}?remove_file=true", get_core(INSTANCE_EXISTS));
+        expect_401(TestRequest::delete(), &remove_file).await;
     }
 
     #[actix_web::test]
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use mercator_db::space::Shape;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::aggregate;
+use super::error_404;
+use super::error_422_field;
+use super::error_500;
+use super::error_504;
+use super::model;
+use super::ok_200;
+use super::web;
+use super::web::Data;
+use super::web::Json;
+use super::web::Path;
+use super::Access;
+use super::ApiError;
+use super::CoreQueryParameters;
+use super::HandlerResult;
+use super::SharedState;
+use crate::shared_state::Cancellation;
+use crate::shared_state::QueryError;
+
+// Upper bound on the number of voxels of a grid, dense grids are allocated
+// as a whole.
+const MAX_VOXELS: usize = 1 << 22;
+
+#[derive(Debug, Deserialize)]
+pub struct DensityParameters {
+    filters: Option<String>, // None selects every object of the core
+    space: String,
+    view_port: (Vec<f64>, Vec<f64>),
+    grid: Vec<usize>, // Number of voxels along each axis
+    resolution: Option<Vec<u32>>, // None means automatic selection, based on ViewPort
+    sparse: Option<bool>,
+    timeout_ms: Option<u64>, // Capped by `MERCATOR_QUERY_TIMEOUT`
+}
+
+impl DensityParameters {
+    pub fn sparse(&self) -> bool {
+        self.sparse.unwrap_or(false)
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn volume(&self) -> f64 {
+        let (low, high) = &self.view_port;
+        Shape::BoundingBox(low.into(), high.into()).volume()
+    }
+
+    // Check the grid against the dimensions of the target space.
+    fn validate(&self, dimensions: usize) -> Result<(), HandlerResult> {
+        let (low, high) = &self.view_port;
+
+        if low.len() != dimensions || high.len() != dimensions {
+            return Err(error_422_field(
+                "view_port",
+                format!("Expected {} coordinates per corner", dimensions),
+            ));
+        }
+        if low.iter().zip(high).any(|(l, h)| l >= h) {
+            return Err(error_422_field(
+                "view_port",
+                "Lower corner must be strictly below the higher one",
+            ));
+        }
+        if self.grid.len() != dimensions {
+            return Err(error_422_field(
+                "grid",
+                format!("Expected {} voxel counts", dimensions),
+            ));
+        }
+
+        let voxels = self
+            .grid
+            .iter()
+            .try_fold(1usize, |total, size| total.checked_mul(*size));
+        match voxels {
+            Some(voxels) if voxels > 0 && voxels <= MAX_VOXELS => Ok(()),
+            _ => Err(error_422_field(
+                "grid",
+                format!("Grids must hold between 1 and {} voxels", MAX_VOXELS),
+            )),
+        }
+    }
+
+    // Row-major index of the voxel holding `position`, if within the view port.
+    fn voxel(&self, position: &[f64]) -> Option<usize> {
+        let (low, high) = &self.view_port;
+        let mut index = 0;
+
+        for (axis, size) in self.grid.iter().enumerate() {
+            let value = *position.get(axis)?;
+            if value < low[axis] || value > high[axis] {
+                return None;
+            }
+
+            // The higher bound belongs to the last voxel.
+            let ratio = (value - low[axis]) / (high[axis] - low[axis]);
+            let offset = (ratio * *size as f64) as usize;
+            index = index * size + offset.min(size - 1);
+        }
+
+        Some(index)
+    }
+
+    fn coordinates(&self, mut index: usize) -> Vec<usize> {
+        let mut coordinates = vec![0; self.grid.len()];
+        for (axis, size) in self.grid.iter().enumerate().rev() {
+            coordinates[axis] = index % size;
+            index /= size;
+        }
+        coordinates
+    }
+}
+
+/// Number of matching positions per voxel.
+#[derive(Debug, Serialize)]
+pub struct Density {
+    space: String,
+    view_port: (Vec<f64>, Vec<f64>),
+    grid: Vec<usize>,
+    positions: u64,
+    // Dense grid, in row-major order: the last axis varies the fastest.
+    counts: Option<Vec<u64>>,
+    // Non-empty voxels only, as (voxel coordinates, count), in row-major order.
+    voxels: Option<Vec<(Vec<usize>, u64)>>,
+}
+
+// Count the positions of the objects of `core_id` matching `parameters` in
+// each voxel of the grid, once the request has been validated.
+fn density(
+    context: &SharedState,
+    core_id: &str,
+    parameters: &DensityParameters,
+    cancellation: &Cancellation,
+) -> Result<Density, ApiError> {
+    let db = context.db();
+    let core = match db.core(core_id) {
+        Err(_) => return error_404().map(|_| unreachable!()),
+        Ok(core) => core,
+    };
+
+    let view_port = Some(parameters.view_port.clone());
+    let core_parameters = CoreQueryParameters {
+        db,
+        output_space: Some(parameters.space.as_str()),
+        threshold_volume: Some(parameters.volume()),
+        view_port: &view_port,
+        resolution: &parameters.resolution,
+    };
+
+    let objects_by_spaces = match &parameters.filters {
+        None => cancellation.guard(aggregate::everything(core, &core_parameters)),
+        Some(filter) => {
+            let tree = context.filter(filter)?;
+            match context.execute(&tree, core_id, &core_parameters, cancellation) {
+                Err(e) => return Err(QueryError::execution(core_id, e).into()),
+                Ok(objects) => objects,
+            }
+        }
+    };
+
+    let mut counts = HashMap::new();
+    let mut positions = 0;
+    for (space, objects) in objects_by_spaces {
+        if space != &parameters.space {
+            continue;
+        }
+
+        for (position, _) in objects {
+            let position: Vec<f64> = position.into();
+            if let Some(voxel) = parameters.voxel(&position) {
+                *counts.entry(voxel).or_insert(0u64) += 1;
+                positions += 1;
+            }
+        }
+    }
+
+    let mut density = Density {
+        space: parameters.space.clone(),
+        view_port: parameters.view_port.clone(),
+        grid: parameters.grid.clone(),
+        positions,
+        counts: None,
+        voxels: None,
+    };
+
+    if parameters.sparse() {
+        let mut voxels = counts.drain().collect::<Vec<_>>();
+        voxels.sort_unstable();
+        density.voxels = Some(
+            voxels
+                .into_iter()
+                .map(|(voxel, count)| (parameters.coordinates(voxel), count))
+                .collect(),
+        );
+    } else {
+        let mut dense = vec![0; parameters.grid.iter().product()];
+        for (voxel, count) in counts {
+            dense[voxel] = count;
+        }
+        density.counts = Some(dense);
+    }
+
+    Ok(density)
+}
+
+async fn post(
+    (path, parameters, state): (
+        Path<String>,
+        Json<DensityParameters>,
+        Data<RwLock<SharedState>>,
+    ),
+) -> HandlerResult {
+    trace!("POST '{:?}', {:?}", parameters, path);
+    let core_id = path.to_string();
+    {
+        let context = state
+            .read()
+            .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+        let db = context.db();
+
+        if Access::current().core(db, &core_id).is_err() {
+            return error_404();
+        }
+        let space = match db.space(&parameters.space) {
+            Err(e) => return error_422_field("space", e),
+            Ok(space) => space,
+        };
+        if let Err(e) = parameters.validate(model::Space::from(space).axes.len()) {
+            return e;
+        }
+    }
+
+    // Positions are counted outside of the HTTP workers, and given up on
+    // when the client goes away.
+    let cancellation = Cancellation::new(parameters.timeout());
+    let _cancel = cancellation.on_drop();
+    let density = {
+        let cancellation = cancellation.clone();
+        let parameters = parameters.into_inner();
+        web::block(move || {
+            let context = state
+                .read()
+                .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+            density(&context, &core_id, &parameters, &cancellation)
+        })
+        .await
+    };
+
+    let density = match density {
+        Err(e) => return error_500(e),
+        Ok(density) => density?,
+    };
+    if cancellation.is_interrupted() {
+        return error_504();
+    }
+
+    ok_200(&density)
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/cores/{name}/density").route(web::post().to(post)));
+}
+
+#[cfg(test)]
+mod routing {
+    use super::super::tests_utils::*;
+    use serde_json::json;
+
+    const INSTANCE_EXISTS: &str = CORE;
+    const INSTANCE_INVALID: &str = "/41-doesnotexists";
+
+    fn get_density(name: &str) -> String {
+        format!("{}{}", get_core(name), "/density")
+    }
+
+    fn density(parameters: serde_json::Value) -> TestRequest {
+        let mut body = json!({
+            "space": "std",
+            "view_port": [[0, 0, 0], [1, 1, 1]],
+            "grid": [4, 4, 4],
+        });
+        for (key, value) in parameters.as_object().unwrap() {
+            body[key] = value.clone();
+        }
+
+        TestRequest::post().set_json(body)
+    }
+
+    #[actix_web::test]
+    async fn post() {
+        let ep = &get_density(INSTANCE_EXISTS);
+
+        expect_200(density(json!({})), ep).await;
+        expect_200(density(json!({"sparse": true})), ep).await;
+        expect_200(
+            density(json!({"filters": "inside(hyperrectangle{[0,0,0],[0,1,1]})"})),
+            ep,
+        )
+        .await;
+
+        expect_404(density(json!({})), &get_density(INSTANCE_INVALID)).await;
+        expect_422(density(json!({"space": "INVALID"})), ep).await;
+        expect_422(density(json!({"grid": [4, 4]})), ep).await;
+        expect_422(density(json!({"grid": [0, 4, 4]})), ep).await;
+        expect_422(density(json!({"grid": [4096, 4096, 4096]})), ep).await;
+        expect_422(density(json!({"view_port": [[1, 0, 0], [0, 1, 1]]})), ep).await;
+        expect_422(density(json!({"filters": "toto"})), ep).await;
+        expect_400(TestRequest::post().set_json(json!({"space": "std"})), ep).await;
+    }
+
+    #[actix_web::test]
+    async fn others() {
+        expect_405(TestRequest::get(), &get_density(INSTANCE_EXISTS)).await;
+        expect_405(TestRequest::put(), &get_density(INSTANCE_EXISTS)).await;
+        expect_405(TestRequest::delete(), &get_density(INSTANCE_EXISTS)).await;
+    }
+}
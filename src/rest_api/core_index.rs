@@ -0,0 +1,186 @@
+use std::any::Any;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::OnceLock;
+use std::sync::RwLock;
+
+use rayon::ThreadPool;
+use rayon::ThreadPoolBuilder;
+use serde::Deserialize;
+
+use super::accepted_202;
+use super::error_404;
+use super::error_409;
+use super::error_422_field;
+use super::ok_200;
+use super::web;
+use super::web::Bytes;
+use super::web::Data;
+use super::web::Path;
+use super::Access;
+use super::HandlerResult;
+use super::Role;
+use super::SharedState;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct IndexParameters {
+    scales: Option<Vec<Vec<u32>>>, // None keeps the scales previously requested
+}
+
+/// Thread pool rebuilding the indices in the background, additional rebuilds
+/// wait for a thread to be available.
+///
+/// It is sized like the query thread pool, by `MERCATOR_QUERY_THREADS`, 0
+/// meaning one thread per logical CPU.
+fn index_pool() -> &'static ThreadPool {
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+    POOL.get_or_init(|| {
+        let threads = std::env::var("MERCATOR_QUERY_THREADS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("mercator-index-{}", i))
+            .build()
+            .unwrap_or_else(|e| panic!("Can't create the index thread pool: {}", e))
+    })
+}
+
+// Describe the reason of a panic, from its payload.
+fn panic_reason(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(reason) => *reason,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(reason) => reason.to_string(),
+            Err(_) => "unknown reason".to_string(),
+        },
+    }
+}
+
+async fn put(
+    (path, body, state): (Path<String>, Bytes, Data<RwLock<SharedState>>),
+) -> HandlerResult {
+    trace!("PUT '{:?}'", path);
+    Access::current().require(Role::Write)?;
+    let name = path.to_string();
+
+    // The body is optional.
+    let parameters = if body.is_empty() {
+        IndexParameters::default()
+    } else {
+        match serde_json::from_slice::<IndexParameters>(&body) {
+            Err(e) => return error_422_field("scales", e),
+            Ok(parameters) => parameters,
+        }
+    };
+
+    // An empty list of scales means automatic selection.
+    let scales = parameters
+        .scales
+        .map(|scales| if scales.is_empty() { None } else { Some(scales) });
+
+    let status = {
+        let mut context = state
+            .write()
+            .unwrap_or_else(|e| panic!("Can't acquire write lock of the database: {}", e));
+
+        if Access::current().core(context.db(), &name).is_err() {
+            return error_404();
+        }
+
+        if !context.start_indexing(&name) {
+            return error_409();
+        }
+
+        context.index_status(&name)
+    };
+
+    // The current index keeps serving queries until the new one is ready.
+    let state = state.clone();
+    index_pool().spawn(move || {
+        // A panic must not leave the rebuild running forever.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let job = state
+                .read()
+                .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e))
+                .index_job(&name, scales)?;
+            let core = job.build();
+
+            Ok::<_, String>((job, core))
+        }))
+        .unwrap_or_else(|payload| Err(format!("Rebuild aborted: {}", panic_reason(payload))));
+
+        state
+            .write()
+            .unwrap_or_else(|e| panic!("Can't acquire write lock of the database: {}", e))
+            .finish_indexing(&name, result);
+    });
+
+    accepted_202(&status)
+}
+
+async fn get((path, state): (Path<String>, Data<RwLock<SharedState>>)) -> HandlerResult {
+    trace!("GET '{:?}'", path);
+    let name = path.to_string();
+    let context = state
+        .read()
+        .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+
+    match Access::current().core(context.db(), &name) {
+        Err(_) => error_404(),
+        Ok(_) => ok_200(&context.index_status(&name)),
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/cores/{name}/index")
+            .route(web::get().to(get))
+            .route(web::put().to(put)),
+    );
+}
+
+#[cfg(test)]
+mod routing {
+    use super::super::tests_utils::*;
+    use serde_json::json;
+
+    const INSTANCE_EXISTS: &str = CORE;
+    const INSTANCE_INVALID: &str = "/41-doesnotexists";
+
+    fn get_index(name: &str) -> String {
+        format!("{}{}", get_core(name), "/index")
+    }
+
+    #[actix_web::test]
+    async fn put() {
+        expect_202(TestRequest::put(), &get_index(INSTANCE_EXISTS)).await;
+        expect_202(
+            TestRequest::put().set_json(json!({"scales": [[0, 0, 0], [1, 1, 1]]})),
+            &get_index(INSTANCE_EXISTS),
+        )
+        .await;
+        expect_422(
+            TestRequest::put().set_json(json!({"scales": [[-1, 0, 0]]})),
+            &get_index(INSTANCE_EXISTS),
+        )
+        .await;
+        expect_404(TestRequest::put(), &get_index(INSTANCE_INVALID)).await;
+    }
+
+    #[actix_web::test]
+    async fn get() {
+        expect_200(TestRequest::get(), &get_index(INSTANCE_EXISTS)).await;
+        expect_404(TestRequest::get(), &get_index(INSTANCE_INVALID)).await;
+    }
+
+    #[actix_web::test]
+    async fn others() {
+        expect_405(TestRequest::post(), &get_index(INSTANCE_EXISTS)).await;
+        expect_405(TestRequest::patch(), &get_index(INSTANCE_EXISTS)).await;
+        expect_405(TestRequest::delete(), &get_index(INSTANCE_EXISTS)).await;
+    }
+}
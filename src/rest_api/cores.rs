@@ -1,91 +1,211 @@
 use std::collections::HashSet;
 use std::sync::RwLock;
 
+use super::aggregate;
+use super::aggregate::Aggregate;
+use super::aggregate::CoreAggregate;
 use super::error_400;
-use super::error_422;
+use super::error_500;
+use super::error_504;
 use super::ok_200;
 use super::web;
 use super::web::Data;
 use super::web::Json;
+use super::Access;
+use super::Aggregation;
+use super::ApiError;
 use super::Core;
 use super::CoreQueryParameters;
 use super::Filters;
 use super::HandlerResult;
 use super::SharedState;
+use crate::shared_state::Cancellation;
+use crate::shared_state::QueryError;
 
-async fn post((parameters, state): (Json<Filters>, Data<RwLock<SharedState>>)) -> HandlerResult {
-    trace!("POST '{:?}'", parameters);
-    let context = state
-        .read()
-        .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+// Outcome of a search over the cores.
+enum Found {
+    Ids(Vec<String>),
+    Cores(Vec<Core>),
+    Aggregate(Aggregate),
+}
+
+// Compute statistics on the objects matching `parameters`, for each core.
+fn aggregate_cores(
+    context: &SharedState,
+    access: &Access,
+    parameters: &Filters,
+    aggregation: Aggregation,
+    cancellation: &Cancellation,
+) -> Result<Aggregate, ApiError> {
     let db = context.db();
+    let core_parameters = CoreQueryParameters {
+        db,
+        output_space: parameters.space.as_ref().map(String::as_str),
+        threshold_volume: parameters.volume(),
+        view_port: &parameters.view_port,
+        resolution: parameters.resolution(),
+    };
 
-    match parameters.space(db) {
-        Err(e) => e,
-        Ok(space) => {
-            match parameters.filters() {
-                None => {
-                    if parameters.ids_only() {
-                        ok_200(db.core_keys())
-                    } else {
-                        let cores = db
-                            .core_keys()
-                            .iter()
-                            .filter_map(|id| match db.core(id) {
-                                Err(_) => None, // FIXME: Return error ?
-                                Ok(x) => Some(Core::from(x)),
-                            })
-                            .collect::<Vec<_>>();
-
-                        ok_200(&cores)
-                    }
-                }
-                Some(filter) => {
-                    let core_parameters = CoreQueryParameters {
-                        db,
-                        output_space: space.as_ref().map(String::as_str),
-                        threshold_volume: parameters.volume(),
-                        view_port: &parameters.view_port,
-                        resolution: parameters.resolution(),
-                    };
-
-                    let tree = match context.filter(filter) {
-                        Err(e) => return error_422(e),
-                        Ok(tree) => tree,
-                    };
-
-                    // Retrieve the list of core ids.
-                    let mut results = HashSet::new();
-                    for core in db.core_keys() {
-                        match context.execute(&tree, core, &core_parameters) {
-                            Err(e) => return error_422(e),
-                            Ok(objects) => {
-                                // If the list of SpaceObjects is not empty, add
-                                // the current core to the list.
-                                if !objects.is_empty() {
-                                    results.insert(core.to_string());
-                                }
-                            }
-                        };
-                    }
+    let tree = match parameters.filters() {
+        None => None,
+        Some(filter) => Some(context.filter(filter)?),
+    };
+    let boxes = aggregate::histogram_boxes(context, parameters);
+
+    let aggregates = context.for_each_core(
+        |core| access.allows(core),
+        |core| match &tree {
+            None => match db.core(core) {
+                Err(e) => Err(QueryError::execution(core, e)),
+                Ok(handle) => Ok(CoreAggregate::new(
+                    core,
+                    cancellation.guard(aggregate::everything(handle, &core_parameters)),
+                    aggregation,
+                    &boxes,
+                )),
+            },
+            Some(tree) => context
+                .execute(tree, core, &core_parameters, cancellation)
+                .map(|objects| CoreAggregate::new(core, objects, aggregation, &boxes))
+                .map_err(|e| QueryError::execution(core, e)),
+        },
+    );
+
+    let mut cores = vec![];
+    for (_, aggregate) in aggregates {
+        match aggregate {
+            Err(e) => return Err(e.into()),
+            Ok(aggregate) => cores.push(aggregate),
+        }
+    }
+
+    Ok(Aggregate::new(cores))
+}
+
+// Search the cores visible to the client, once the output space of
+// `parameters` has been validated.
+fn search(
+    context: &SharedState,
+    access: &Access,
+    parameters: &Filters,
+    cancellation: &Cancellation,
+) -> Result<Found, ApiError> {
+    let db = context.db();
+
+    if let Some(aggregation) = parameters.aggregate() {
+        return aggregate_cores(context, access, parameters, aggregation, cancellation)
+            .map(Found::Aggregate);
+    }
+
+    let results = match parameters.filters() {
+        None => db
+            .core_keys()
+            .iter()
+            .filter(|id| access.allows(id))
+            .cloned()
+            .collect::<Vec<_>>(),
+        Some(filter) => {
+            let core_parameters = CoreQueryParameters {
+                db,
+                output_space: parameters.space.as_ref().map(String::as_str),
+                threshold_volume: parameters.volume(),
+                view_port: &parameters.view_port,
+                resolution: parameters.resolution(),
+            };
+
+            let tree = context.filter(filter)?;
+
+            // Retrieve the list of core ids.
+            let mut results = HashSet::new();
+            let matches = context.for_each_core(
+                |core| access.allows(core),
+                |core| {
+                    context
+                        .execute(&tree, core, &core_parameters, cancellation)
+                        .map(|objects| !objects.is_empty())
+                        .map_err(|e| QueryError::execution(core, e))
+                },
+            );
 
-                    // Format the list or the whole core objects.
-                    if parameters.ids_only() {
-                        ok_200(&results.drain().collect::<Vec<_>>())
-                    } else {
-                        ok_200(
-                            &results
-                                .drain()
-                                .map(|x| Core::from(db.core(&x).unwrap()))
-                                .collect::<Vec<_>>(),
-                        )
+            for (core, matched) in matches {
+                match matched {
+                    Err(e) => return Err(e.into()),
+                    Ok(matched) => {
+                        // If the list of SpaceObjects is not empty, add
+                        // the current core to the list.
+                        if matched {
+                            results.insert(core.to_string());
+                        }
                     }
-                }
+                };
             }
+
+            results.drain().collect()
+        }
+    };
+
+    // Format the list or the whole core objects.
+    if parameters.ids_only() {
+        Ok(Found::Ids(results))
+    } else {
+        let cores = results
+            .iter()
+            .filter_map(|id| match db.core(id) {
+                Err(_) => None, // FIXME: Return error ?
+                Ok(x) => Some(Core::describe(context, x)),
+            })
+            .collect::<Result<Vec<_>, _>>();
+
+        match cores {
+            Err(e) => error_500(e).map(|_| unreachable!()),
+            Ok(cores) => Ok(Found::Cores(cores)),
         }
     }
 }
 
+async fn post((parameters, state): (Json<Filters>, Data<RwLock<SharedState>>)) -> HandlerResult {
+    trace!("POST '{:?}'", parameters);
+    {
+        let context = state
+            .read()
+            .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+        if let Err(e) = parameters.space(context.db()) {
+            return e;
+        }
+    }
+
+    // Cores are searched outside of the HTTP workers, and given up on when
+    // the client goes away.
+    let access = Access::current();
+    let cancellation = Cancellation::new(parameters.timeout());
+    let _cancel = cancellation.on_drop();
+    let found = {
+        let cancellation = cancellation.clone();
+        let parameters = parameters.into_inner();
+        web::block(move || {
+            let context = state
+                .read()
+                .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+            search(&context, &access, &parameters, &cancellation)
+        })
+        .await
+    };
+
+    let found = match found {
+        Err(e) => return error_500(e),
+        Ok(found) => found?,
+    };
+    if cancellation.is_interrupted() {
+        return error_504();
+    }
+
+    match found {
+        Found::Ids(ids) => ok_200(&ids),
+        Found::Cores(cores) => ok_200(&cores),
+        Found::Aggregate(aggregate) => ok_200(&aggregate),
+    }
+}
+
 async fn put() -> HandlerResult {
     trace!("PUT Triggered!");
     error_400()
@@ -114,6 +234,7 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 #[cfg(test)]
 mod routing {
     use super::super::tests_utils::*;
+    use serde_json::json;
 
     // FIXME: Add Body to request to see difference between (in)valid bodied requests
 
@@ -127,6 +248,22 @@ mod routing {
         expect_400(TestRequest::post(), &get_core("")).await;
     }
 
+    #[actix_web::test]
+    async fn post_aggregate() {
+        let aggregate = |parameters| TestRequest::post().set_json(parameters);
+        let filters = "inside(hyperrectangle{[0,0,0],[0,1,1]})";
+
+        expect_200(aggregate(json!({"aggregate": "count"})), &get_core("")).await;
+        expect_200(
+            aggregate(json!({"aggregate": "bbox", "filters": filters})),
+            &get_core(""),
+        )
+        .await;
+        expect_422(aggregate(json!({"aggregate": "count", "space": "INVALID"})), &get_core(""))
+            .await;
+        expect_400(aggregate(json!({"aggregate": "sum"})), &get_core("")).await;
+    }
+
     #[actix_web::test]
     async fn put() {
         json::expect_200(TestRequest::put(), &get_core(""), "".to_string()).await;
@@ -0,0 +1,211 @@
+use std::future::Future;
+
+use actix_web::http::header;
+use actix_web::http::header::HeaderMap;
+use serde::Serialize;
+
+tokio::task_local! {
+    // Encoding negotiated for the request being handled.
+    static ENCODING: Encoding;
+}
+
+/// Encodings available for successful responses, negotiated through the
+/// `Accept` header. Errors are always Problem Details JSON documents.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+    Bincode,
+}
+
+impl Encoding {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::MessagePack => "application/msgpack",
+            Encoding::Cbor => "application/cbor",
+            Encoding::Bincode => "application/x-bincode",
+        }
+    }
+
+    fn from_media_type(media: &str) -> Option<Self> {
+        match media {
+            "application/json" => Some(Encoding::Json),
+            "application/msgpack" | "application/x-msgpack" => Some(Encoding::MessagePack),
+            "application/cbor" => Some(Encoding::Cbor),
+            "application/x-bincode" => Some(Encoding::Bincode),
+            _ => None,
+        }
+    }
+
+    /// First supported media type of the `Accept` header, by decreasing
+    /// quality. Defaults to JSON.
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        let mut accepted = headers
+            .get_all(header::ACCEPT)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|range| {
+                let mut parameters = range.split(';').map(str::trim);
+                let encoding = Encoding::from_media_type(parameters.next()?)?;
+                let quality = parameters
+                    .filter_map(|parameter| parameter.strip_prefix("q="))
+                    .filter_map(|q| q.parse::<f32>().ok())
+                    .next()
+                    .unwrap_or(1.0);
+
+                Some((encoding, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect::<Vec<_>>();
+
+        // Stable, so ties keep the order of the header.
+        accepted.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+
+        accepted
+            .first()
+            .map(|(encoding, _)| *encoding)
+            .unwrap_or(Encoding::Json)
+    }
+
+    /// Encoding negotiated for the current request.
+    pub fn current() -> Self {
+        ENCODING.try_with(|encoding| *encoding).unwrap_or(Encoding::Json)
+    }
+
+    /// Handle the request of `future` with `self` as the negotiated encoding.
+    pub fn scope<F>(self, future: F) -> impl Future<Output = F::Output>
+    where
+        F: Future,
+    {
+        ENCODING.scope(self, future)
+    }
+
+    /// Serialize `data`.
+    ///
+    /// Structures are encoded as maps keyed by field names in MessagePack and
+    /// CBOR, exactly as in JSON. Bincode uses the default bincode 1.x layout:
+    /// fields in declaration order, little-endian fixed-size integers, and
+    /// u64 lengths prefixing strings and sequences.
+    pub fn encode<T>(self, data: &T) -> Result<Vec<u8>, String>
+    where
+        T: Serialize,
+    {
+        match self {
+            Encoding::Json => serde_json::to_vec(data).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::to_vec_named(data).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut buffer = vec![];
+                ciborium::into_writer(data, &mut buffer).map_err(|e| e.to_string())?;
+                Ok(buffer)
+            }
+            Encoding::Bincode => bincode::serialize(data).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn negotiate() {
+        let accept = |value: &'static str| {
+            let request = TestRequest::default()
+                .insert_header((header::ACCEPT, value))
+                .to_http_request();
+            Encoding::negotiate(request.headers())
+        };
+
+        assert_eq!(accept("*/*"), Encoding::Json);
+        assert_eq!(accept("text/html, application/msgpack"), Encoding::MessagePack);
+        assert_eq!(accept("application/x-msgpack"), Encoding::MessagePack);
+        assert_eq!(accept("application/cbor;q=0.5, application/x-bincode"), Encoding::Bincode);
+        assert_eq!(accept("application/cbor, application/json;q=0.9"), Encoding::Cbor);
+        assert_eq!(accept("application/cbor;q=0"), Encoding::Json);
+    }
+}
+
+#[cfg(test)]
+mod routing {
+    use super::super::tests_utils::*;
+    use super::super::Role;
+    use super::super::Tokens;
+    use super::Encoding;
+    use actix_web::http::header;
+    use actix_web::http::StatusCode;
+    use serde::de::DeserializeOwned;
+    use std::fmt::Debug;
+
+    /// Retrieve the payload of `path`, encoded as requested.
+    async fn fetch<T>(method: TestRequest, path: &str, encoding: Encoding) -> T
+    where
+        T: DeserializeOwned,
+    {
+        let request = method
+            .uri(path)
+            .insert_header((header::ACCEPT, encoding.content_type()));
+        let reply = call(Tokens::anonymous(Role::Write), None, vec![request])
+            .await
+            .remove(0);
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(
+            reply.headers.get(header::CONTENT_TYPE).unwrap(),
+            encoding.content_type()
+        );
+
+        match encoding {
+            Encoding::Json => serde_json::from_slice(&reply.body).unwrap(),
+            Encoding::MessagePack => rmp_serde::from_slice(&reply.body).unwrap(),
+            Encoding::Cbor => ciborium::from_reader(&reply.body[..]).unwrap(),
+            Encoding::Bincode => bincode::deserialize(&reply.body).unwrap(),
+        }
+    }
+
+    /// Checks the payload decoded from `encoding` matches the JSON one.
+    async fn expect_round_trip<T, M>(method: M, path: &str, encoding: Encoding)
+    where
+        T: DeserializeOwned + PartialEq + Debug,
+        M: Fn() -> TestRequest,
+    {
+        let expected = fetch::<T>(method(), path, Encoding::Json).await;
+        let actual = fetch::<T>(method(), path, encoding).await;
+        assert_eq!(actual, expected);
+    }
+
+    #[actix_web::test]
+    async fn round_trip_json() {
+        expect_round_trip::<Vec<String>, _>(TestRequest::post, &get_core(""), Encoding::Json)
+            .await;
+    }
+
+    #[actix_web::test]
+    async fn round_trip_message_pack() {
+        let encoding = Encoding::MessagePack;
+        expect_round_trip::<Vec<String>, _>(TestRequest::post, &get_core(""), encoding).await;
+        expect_round_trip::<serde_json::Value, _>(TestRequest::get, &get_space(SPACE), encoding)
+            .await;
+        expect_round_trip::<serde_json::Value, _>(TestRequest::get, &get_core(CORE), encoding)
+            .await;
+    }
+
+    #[actix_web::test]
+    async fn round_trip_cbor() {
+        let encoding = Encoding::Cbor;
+        expect_round_trip::<Vec<String>, _>(TestRequest::post, &get_core(""), encoding).await;
+        expect_round_trip::<serde_json::Value, _>(TestRequest::get, &get_space(SPACE), encoding)
+            .await;
+        expect_round_trip::<serde_json::Value, _>(TestRequest::get, &get_core(CORE), encoding)
+            .await;
+    }
+
+    #[actix_web::test]
+    async fn round_trip_bincode() {
+        // Bincode is not self-describing, the expected type must be known.
+        let encoding = Encoding::Bincode;
+        expect_round_trip::<Vec<String>, _>(TestRequest::post, &get_core(""), encoding).await;
+        expect_round_trip::<Vec<String>, _>(TestRequest::post, &get_space(""), encoding).await;
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+
+use actix_web::http::header;
+use actix_web::http::header::HeaderValue;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use actix_web::ResponseError;
+use serde::Serialize;
+
+use crate::shared_state::QueryError;
+use crate::shared_state::Stage;
+
+/// Machine readable kind of error, to be used by clients instead of
+/// parsing the human readable message.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    TooManyRequests,
+    InvalidParameter,
+    InvalidQuery,
+    InternalError,
+    Timeout,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::InvalidParameter => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::InvalidQuery => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// Code to use for an error response produced outside of the handlers,
+    /// for example by the router or the extractors.
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::TooManyRequests,
+            StatusCode::GATEWAY_TIMEOUT => ErrorCode::Timeout,
+            StatusCode::UNPROCESSABLE_ENTITY => ErrorCode::InvalidParameter,
+            s if s.is_server_error() => ErrorCode::InternalError,
+            _ => ErrorCode::BadRequest,
+        }
+    }
+}
+
+/// Error returned by the handlers, serialized as a Problem Details
+/// document (RFC 7807).
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    code: ErrorCode,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>, // Offending field of the request body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage: Option<Stage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    span: Option<(usize, usize)>, // Offending part of the query string
+    #[serde(skip_serializing_if = "Option::is_none")]
+    core: Option<String>,
+    #[serde(skip)]
+    retry_after: Option<u64>, // Seconds, sent as the `Retry-After` header
+}
+
+impl ApiError {
+    pub fn new<S>(code: ErrorCode, detail: S) -> Self
+    where
+        S: Into<String>,
+    {
+        let status = code.status();
+
+        ApiError {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Unknown Error"),
+            status: status.as_u16(),
+            code,
+            detail: detail.into(),
+            field: None,
+            stage: None,
+            span: None,
+            core: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn with_field<S>(mut self, field: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.field = Some(field.into());
+        self
+    }
+
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        self.retry_after
+    }
+}
+
+impl From<QueryError> for ApiError {
+    fn from(error: QueryError) -> Self {
+        let mut api_error = ApiError::new(ErrorCode::InvalidQuery, error.message().clone());
+        api_error.stage = Some(error.stage());
+        api_error.span = *error.position();
+        api_error.core = error.core().clone();
+
+        api_error
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} - {}: {}", self.status, self.title, self.detail)
+    }
+}
+
+// Serialize `error` as a Problem Details document, whichever the client.
+fn render(error: &ApiError) -> HttpResponse {
+    match serde_json::to_string(error) {
+        Ok(body) => HttpResponse::build(error.status_code())
+            .content_type("application/problem+json")
+            .body(body),
+        Err(_) => HttpResponse::build(error.status_code()).finish(),
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = render(self);
+        if let Some(seconds) = self.retry_after() {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn problem_json() {
+        let error = ApiError::new(ErrorCode::InvalidParameter, "Invalid space").with_field("space");
+        let json = serde_json::to_value(&error).unwrap();
+
+        assert_eq!(json["type"], "about:blank");
+        assert_eq!(json["status"], 422);
+        assert_eq!(json["code"], "invalid_parameter");
+        assert_eq!(json["field"], "space");
+        assert!(json.get("span").is_none());
+    }
+
+    #[test]
+    fn query_error() {
+        let error: ApiError = QueryError::execution("10k", "failed".to_string()).into();
+        let json = serde_json::to_value(&error).unwrap();
+
+        assert_eq!(json["code"], "invalid_query");
+        assert_eq!(json["stage"], "execution");
+        assert_eq!(json["core"], "10k");
+    }
+}
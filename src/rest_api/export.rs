@@ -0,0 +1,547 @@
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::fmt::Write;
+
+use actix_web::http::header;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::error_500;
+use super::model;
+use super::HandlerResult;
+use super::HttpResponse;
+use crate::shared_state::Shapes;
+
+// Tessellation of hyperspheres, as latitude and longitude subdivisions.
+const RINGS: u32 = 8;
+const SEGMENTS: u32 = 16;
+
+const BOUNDARY: &str = "mercator-export-7d3c1f0b2a9e4c58";
+
+/// Header set on the exports of truncated results, as files have no room for
+/// the `truncated` flag.
+pub const TRUNCATED: &str = "x-truncated";
+
+/// File formats available to export spatial objects to visualization tools.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Ply,
+    Obj,
+    Gltf,
+    Csv,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Ply => "ply",
+            Format::Obj => "obj",
+            Format::Gltf => "gltf",
+            Format::Csv => "csv",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Ply => "application/x-ply",
+            Format::Obj => "model/obj",
+            Format::Gltf => "model/gltf+json",
+            Format::Csv => "text/csv",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Primitive {
+    Point(Vec<f64>),
+    Box(Vec<f64>, Vec<f64>),
+    Sphere(Vec<f64>, f64),
+}
+
+#[derive(Debug)]
+struct Record<'o> {
+    id: &'o str,
+    type_name: &'o str,
+    primitive: Primitive,
+}
+
+// Point cloud and triangle mesh of the shapes of one reference space.
+#[derive(Debug, Default)]
+struct Mesh {
+    points: Vec<[f64; 3]>,
+    vertices: Vec<[f64; 3]>,
+    triangles: Vec<[u32; 3]>,
+}
+
+// Only the first three dimensions are exported, missing ones are set to 0.
+fn xyz(position: &[f64]) -> [f64; 3] {
+    let mut point = [0.0; 3];
+    for (axis, value) in point.iter_mut().zip(position) {
+        *axis = *value;
+    }
+    point
+}
+
+impl Mesh {
+    fn new(records: &[Record]) -> Self {
+        let mut mesh = Mesh::default();
+
+        for record in records {
+            match &record.primitive {
+                Primitive::Point(position) => mesh.points.push(xyz(position)),
+                Primitive::Box(low, high) => mesh.add_box(xyz(low), xyz(high)),
+                Primitive::Sphere(center, radius) => mesh.add_sphere(xyz(center), *radius),
+            }
+        }
+
+        mesh
+    }
+
+    fn add_box(&mut self, low: [f64; 3], high: [f64; 3]) {
+        let base = self.vertices.len() as u32;
+        for corner in 0..8 {
+            let pick = |axis: usize| {
+                if corner & (1 << axis) == 0 {
+                    low[axis]
+                } else {
+                    high[axis]
+                }
+            };
+            self.vertices.push([pick(0), pick(1), pick(2)]);
+        }
+
+        let faces: [[u32; 4]; 6] = [
+            [0, 2, 3, 1],
+            [4, 5, 7, 6],
+            [0, 1, 5, 4],
+            [2, 6, 7, 3],
+            [0, 4, 6, 2],
+            [1, 3, 7, 5],
+        ];
+        for [a, b, c, d] in faces.iter() {
+            self.triangles.push([base + a, base + b, base + c]);
+            self.triangles.push([base + a, base + c, base + d]);
+        }
+    }
+
+    fn add_sphere(&mut self, center: [f64; 3], radius: f64) {
+        let base = self.vertices.len() as u32;
+        for ring in 0..=RINGS {
+            let theta = PI * f64::from(ring) / f64::from(RINGS);
+            for segment in 0..=SEGMENTS {
+                let phi = 2.0 * PI * f64::from(segment) / f64::from(SEGMENTS);
+                self.vertices.push([
+                    center[0] + radius * theta.sin() * phi.cos(),
+                    center[1] + radius * theta.sin() * phi.sin(),
+                    center[2] + radius * theta.cos(),
+                ]);
+            }
+        }
+
+        for ring in 0..RINGS {
+            for segment in 0..SEGMENTS {
+                let a = base + ring * (SEGMENTS + 1) + segment;
+                let b = a + SEGMENTS + 1;
+                self.triangles.push([a, b, a + 1]);
+                self.triangles.push([a + 1, b, b + 1]);
+            }
+        }
+    }
+}
+
+fn ply(space: &str, records: &[Record]) -> String {
+    let mesh = Mesh::new(records);
+    let offset = mesh.points.len() as u32;
+    let mut file = String::new();
+
+    // Writing to a String never fails.
+    let _ = write!(
+        file,
+        "ply\nformat ascii 1.0\ncomment space {}\nelement vertex {}\n\
+         property double x\nproperty double y\nproperty double z\n\
+         element face {}\nproperty list uchar uint vertex_indices\nend_header\n",
+        space,
+        mesh.points.len() + mesh.vertices.len(),
+        mesh.triangles.len()
+    );
+    for [x, y, z] in mesh.points.iter().chain(mesh.vertices.iter()) {
+        let _ = writeln!(file, "{} {} {}", x, y, z);
+    }
+    for [a, b, c] in &mesh.triangles {
+        let _ = writeln!(file, "3 {} {} {}", a + offset, b + offset, c + offset);
+    }
+
+    file
+}
+
+fn obj(space: &str, records: &[Record]) -> String {
+    let mut file = format!("# space {}\n", space);
+    let mut count = 0;
+
+    // One group per spatial object, indices are 1-based and global.
+    for record in records {
+        let mesh = Mesh::new(std::slice::from_ref(record));
+        let _ = writeln!(file, "g {}", record.id);
+        for [x, y, z] in mesh.points.iter().chain(mesh.vertices.iter()) {
+            let _ = writeln!(file, "v {} {} {}", x, y, z);
+        }
+        for index in 0..mesh.points.len() {
+            let _ = writeln!(file, "p {}", count + index + 1);
+        }
+        let offset = count + mesh.points.len() + 1;
+        for [a, b, c] in &mesh.triangles {
+            let [a, b, c] = [*a as usize, *b as usize, *c as usize];
+            let _ = writeln!(file, "f {} {} {}", a + offset, b + offset, c + offset);
+        }
+        count += mesh.points.len() + mesh.vertices.len();
+    }
+
+    file
+}
+
+fn csv(records: &[Record]) -> String {
+    let dimensions = records
+        .iter()
+        .map(|record| match &record.primitive {
+            Primitive::Point(position) | Primitive::Sphere(position, _) => position.len(),
+            Primitive::Box(low, high) => low.len().max(high.len()),
+        })
+        .max()
+        .unwrap_or(0);
+
+    let coordinates = |position: Option<&Vec<f64>>| {
+        (0..dimensions)
+            .map(|axis| match position.and_then(|p| p.get(axis)) {
+                None => String::new(),
+                Some(value) => value.to_string(),
+            })
+            .collect::<Vec<_>>()
+    };
+    let quote = |field: &str| format!("\"{}\"", field.replace('"', "\"\""));
+
+    let mut header = vec!["id".to_string(), "type".to_string(), "shape".to_string()];
+    header.extend((0..dimensions).map(|axis| format!("x{}", axis)));
+    header.extend((0..dimensions).map(|axis| format!("high_x{}", axis)));
+    header.push("radius".to_string());
+
+    let mut file = header.join(",");
+    file.push('\n');
+    for record in records {
+        let (shape, position, high, radius) = match &record.primitive {
+            Primitive::Point(position) => ("point", position, None, String::new()),
+            Primitive::Box(low, high) => ("boundingbox", low, Some(high), String::new()),
+            Primitive::Sphere(center, radius) => {
+                ("hypersphere", center, None, radius.to_string())
+            }
+        };
+
+        let mut row = vec![quote(record.id), quote(record.type_name), shape.to_string()];
+        row.extend(coordinates(Some(position)));
+        row.extend(coordinates(high));
+        row.push(radius);
+
+        file.push_str(&row.join(","));
+        file.push('\n');
+    }
+
+    file
+}
+
+fn gltf(space: &str, records: &[Record]) -> String {
+    let mesh = Mesh::new(records);
+    let mut buffer = vec![];
+    let mut views = vec![];
+    let mut accessors = vec![];
+    let mut primitives = vec![];
+
+    let mut add_positions = |buffer: &mut Vec<u8>, positions: &[[f64; 3]]| {
+        let offset = buffer.len();
+        let mut min = [f64::INFINITY; 3];
+        let mut max = [f64::NEG_INFINITY; 3];
+        for position in positions {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(position[axis]);
+                max[axis] = max[axis].max(position[axis]);
+                buffer.extend_from_slice(&(position[axis] as f32).to_le_bytes());
+            }
+        }
+
+        views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": buffer.len() - offset,
+            "target": 34962, // ARRAY_BUFFER
+        }));
+        accessors.push(json!({
+            "bufferView": views.len() - 1,
+            "componentType": 5126, // FLOAT
+            "count": positions.len(),
+            "type": "VEC3",
+            "min": min.iter().map(|v| *v as f32).collect::<Vec<_>>(),
+            "max": max.iter().map(|v| *v as f32).collect::<Vec<_>>(),
+        }));
+
+        accessors.len() - 1
+    };
+
+    // glTF forbids empty buffer views, so only the non-empty parts are kept.
+    let points = if mesh.points.is_empty() {
+        None
+    } else {
+        Some(add_positions(&mut buffer, &mesh.points))
+    };
+    let vertices = if mesh.triangles.is_empty() {
+        None
+    } else {
+        Some(add_positions(&mut buffer, &mesh.vertices))
+    };
+
+    if let Some(points) = points {
+        primitives.push(json!({"attributes": {"POSITION": points}, "mode": 0}));
+    }
+    if let Some(vertices) = vertices {
+        let offset = buffer.len();
+        for index in mesh.triangles.iter().flatten() {
+            buffer.extend_from_slice(&index.to_le_bytes());
+        }
+
+        views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": buffer.len() - offset,
+            "target": 34963, // ELEMENT_ARRAY_BUFFER
+        }));
+        accessors.push(json!({
+            "bufferView": views.len() - 1,
+            "componentType": 5125, // UNSIGNED_INT
+            "count": mesh.triangles.len() * 3,
+            "type": "SCALAR",
+        }));
+        primitives.push(json!({
+            "attributes": {"POSITION": vertices},
+            "indices": accessors.len() - 1,
+            "mode": 4,
+        }));
+    }
+
+    let mut document = json!({
+        "asset": {"version": "2.0", "generator": "mercator_service"},
+        "scene": 0,
+        "scenes": [{"nodes": [0]}],
+        "nodes": [{"name": space}],
+    });
+    if !primitives.is_empty() {
+        document["nodes"][0]["mesh"] = json!(0);
+        document["meshes"] = json!([{"name": space, "primitives": primitives}]);
+        document["buffers"] = json!([{
+            "byteLength": buffer.len(),
+            "uri": format!("data:application/octet-stream;base64,{}", STANDARD.encode(&buffer)),
+        }]);
+        document["bufferViews"] = json!(views);
+        document["accessors"] = json!(accessors);
+    }
+
+    document.to_string()
+}
+
+// Group the shapes of `objects` by reference space.
+fn by_spaces(
+    objects: &[model::v2::SpatialObject],
+) -> Result<BTreeMap<&str, Vec<Record>>, String> {
+    let mut spaces = BTreeMap::new();
+
+    for object in objects {
+        for volume in &object.volumes {
+            let records = spaces.entry(volume.space.as_str()).or_insert_with(Vec::new);
+            let record = |primitive| Record {
+                id: &object.properties.id,
+                type_name: &object.properties.type_name,
+                primitive,
+            };
+
+            for shape in &volume.shapes {
+                let shapes = Shapes::from_model(shape)?;
+
+                match shapes {
+                    Shapes::Points(points) => {
+                        records.extend(points.into_iter().map(|p| record(Primitive::Point(p))))
+                    }
+                    Shapes::BoundingBoxes(boxes) => records.extend(
+                        boxes
+                            .into_iter()
+                            .map(|(low, high)| record(Primitive::Box(low, high))),
+                    ),
+                    Shapes::HyperSpheres(spheres) => records.extend(
+                        spheres
+                            .into_iter()
+                            .map(|(center, radius)| record(Primitive::Sphere(center, radius))),
+                    ),
+                }
+            }
+        }
+    }
+
+    Ok(spaces)
+}
+
+/// Export `objects` as one file per reference space. A single file is
+/// returned as is, otherwise the files are the parts of a multipart/mixed
+/// response.
+///
+/// Without objects, an empty file is returned for the output `space`, or no
+/// content when the objects are kept in their own space.
+pub fn render(
+    format: Format,
+    space: Option<&str>,
+    objects: &[model::v2::SpatialObject],
+    truncated: bool,
+) -> HandlerResult {
+    let mut spaces = match by_spaces(objects) {
+        Err(e) => return error_500(e),
+        Ok(spaces) => spaces,
+    };
+
+    if spaces.is_empty() {
+        match space {
+            Some(space) => {
+                spaces.insert(space, vec![]);
+            }
+            None => {
+                let mut response = HttpResponse::NoContent();
+                if truncated {
+                    response.insert_header((TRUNCATED, "true"));
+                }
+                return Ok(response.finish());
+            }
+        }
+    }
+
+    let files = spaces
+        .iter()
+        .map(|(space, records)| {
+            let file = match format {
+                Format::Ply => ply(space, records),
+                Format::Obj => obj(space, records),
+                Format::Gltf => gltf(space, records),
+                Format::Csv => csv(records),
+            };
+            let disposition = format!(
+                "attachment; filename=\"{}.{}\"",
+                space.replace(|c: char| c == '"' || c == '\\' || c.is_control(), "_"),
+                format.extension()
+            );
+
+            (disposition, file)
+        })
+        .collect::<Vec<_>>();
+
+    let mut response = HttpResponse::Ok();
+    if truncated {
+        response.insert_header((TRUNCATED, "true"));
+    }
+
+    if files.len() == 1 {
+        let (disposition, file) = files.into_iter().next().unwrap();
+        return Ok(response
+            .content_type(format.content_type())
+            .insert_header((header::CONTENT_DISPOSITION, disposition))
+            .body(file));
+    }
+
+    let mut body = String::new();
+    for (disposition, file) in files {
+        let _ = write!(
+            body,
+            "--{}\r\nContent-Type: {}\r\nContent-Disposition: {}\r\n\r\n{}\r\n",
+            BOUNDARY,
+            format.content_type(),
+            disposition,
+            file
+        );
+    }
+    let _ = write!(body, "--{}--\r\n", BOUNDARY);
+
+    Ok(response
+        .content_type(format!("multipart/mixed; boundary={}", BOUNDARY))
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<Record<'static>> {
+        let record = |primitive| Record {
+            id: "oid",
+            type_name: "Feature",
+            primitive,
+        };
+
+        vec![
+            record(Primitive::Point(vec![0.5, 0.5, 0.5])),
+            record(Primitive::Box(vec![0.0, 0.0, 0.0], vec![1.0, 1.0, 1.0])),
+            record(Primitive::Sphere(vec![0.0, 0.0], 2.0)),
+        ]
+    }
+
+    #[test]
+    fn mesh() {
+        let mesh = Mesh::new(&records());
+        let sphere = ((RINGS + 1) * (SEGMENTS + 1)) as usize;
+
+        assert_eq!(mesh.points, vec![[0.5, 0.5, 0.5]]);
+        assert_eq!(mesh.vertices.len(), 8 + sphere);
+        assert_eq!(mesh.triangles.len(), 12 + (2 * RINGS * SEGMENTS) as usize);
+        assert!(mesh
+            .triangles
+            .iter()
+            .flatten()
+            .all(|index| (*index as usize) < mesh.vertices.len()));
+    }
+
+    #[test]
+    fn files() {
+        let records = records();
+
+        let ply = ply("std", &records);
+        assert!(ply.starts_with("ply\nformat ascii 1.0\ncomment space std\n"));
+        assert!(ply.contains(&format!("element vertex {}\n", 1 + 8 + 17 * 9)));
+
+        let obj = obj("std", &records);
+        assert!(obj.contains("p 1\n"));
+        assert!(obj.contains("f 2 4 5\n"));
+
+        let csv = csv(&records);
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("id,type,shape,x0,x1,x2,high_x0,high_x1,high_x2,radius")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("\"oid\",\"Feature\",point,0.5,0.5,0.5,,,,")
+        );
+        assert_eq!(lines.last(), Some("\"oid\",\"Feature\",hypersphere,0,0,,,,,2"));
+
+        let gltf: serde_json::Value = serde_json::from_str(&gltf("std", &records)).unwrap();
+        assert_eq!(gltf["asset"]["version"], "2.0");
+        assert_eq!(gltf["meshes"][0]["primitives"].as_array().unwrap().len(), 2);
+        assert_eq!(gltf["accessors"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn empty() {
+        let response = render(Format::Ply, Some("std"), &[], false).unwrap();
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+            "attachment; filename=\"std.ply\""
+        );
+
+        let response = render(Format::Ply, None, &[], false).unwrap();
+        assert_eq!(response.status(), actix_web::http::StatusCode::NO_CONTENT);
+    }
+}
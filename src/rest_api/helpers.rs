@@ -1,50 +1,150 @@
-use std::fmt::Debug;
+use std::fmt::Display;
 use std::io::Error;
-use std::io::ErrorKind;
 
+use actix_web::body::BoxBody;
+use actix_web::body::EitherBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header;
+use actix_web::middleware::ErrorHandlerResponse;
+use actix_web::ResponseError;
 use serde::Serialize;
 
-use super::error_404;
 use super::web::Path;
-use super::Either;
+use super::ApiError;
+use super::Encoding;
+use super::ErrorCode;
 use super::HandlerResult;
+use super::missing_file;
 use super::HttpResponse;
 use super::NamedFile;
-use super::StatusCode;
 
-pub fn ok_200<T>(data: &T) -> HandlerResult
+// Serialize `data` with the encoding negotiated for the current request.
+fn encode<T>(mut response: actix_web::HttpResponseBuilder, data: &T) -> HandlerResult
 where
     T: Serialize,
 {
-    match serde_json::to_string(data) {
-        Ok(response) => Ok(Either::Left(
-            HttpResponse::Ok()
-                .content_type("application/json")
-                .body(response),
-        )),
+    let encoding = Encoding::current();
+    match encoding.encode(data) {
+        Ok(body) => Ok(response
+            .content_type(encoding.content_type())
+            .insert_header((header::VARY, "Accept"))
+            .body(body)),
         Err(e) => error_500(e),
     }
 }
 
+pub fn ok_200<T>(data: &T) -> HandlerResult
+where
+    T: Serialize,
+{
+    encode(HttpResponse::Ok(), data)
+}
+
+pub fn accepted_202<T>(data: &T) -> HandlerResult
+where
+    T: Serialize,
+{
+    encode(HttpResponse::Accepted(), data)
+}
+
+pub fn error_400() -> HandlerResult {
+    Err(ApiError::new(
+        ErrorCode::BadRequest,
+        "Invalid or malformed request",
+    ))
+}
+
+pub fn error_401() -> HandlerResult {
+    Err(ApiError::new(
+        ErrorCode::Unauthorized,
+        "Missing or invalid credentials",
+    ))
+}
+
+pub fn error_403() -> HandlerResult {
+    Err(ApiError::new(
+        ErrorCode::Forbidden,
+        "Insufficient permissions",
+    ))
+}
+
+pub fn error_404() -> HandlerResult {
+    Err(ApiError::new(ErrorCode::NotFound, "Object not found"))
+}
+
+pub fn error_409() -> HandlerResult {
+    Err(ApiError::new(
+        ErrorCode::Conflict,
+        "Conflict with the current state of the database",
+    ))
+}
+
 pub fn error_422<S>(reason: S) -> HandlerResult
 where
-    S: Debug,
+    S: Display,
 {
-    Ok(Either::Left(HttpResponse::UnprocessableEntity().body(
-        format!("422 - Unprocessable Entity:\n{:?}", reason),
-    )))
+    Err(ApiError::new(ErrorCode::InvalidParameter, reason.to_string()))
+}
+
+pub fn error_422_field<S>(field: &str, reason: S) -> HandlerResult
+where
+    S: Display,
+{
+    Err(ApiError::new(ErrorCode::InvalidParameter, reason.to_string()).with_field(field))
 }
 
 pub fn error_500<S>(reason: S) -> HandlerResult
 where
-    S: Debug,
+    S: Display,
 {
-    Err(Error::new(
-        ErrorKind::Other,
-        format!("500 - Internal Server Error: {:?}", reason),
+    error!("500 - Internal Server Error: {}", reason);
+    Err(ApiError::new(ErrorCode::InternalError, reason.to_string()))
+}
+
+pub fn error_504() -> HandlerResult {
+    Err(ApiError::new(
+        ErrorCode::Timeout,
+        "Query cancelled before completion, narrow it or raise its timeout",
     ))
 }
 
+/// Report extractor failures, for example an invalid JSON body.
+pub fn bad_request<E>(error: E) -> actix_web::Error
+where
+    E: Display,
+{
+    ApiError::new(ErrorCode::BadRequest, error.to_string()).into()
+}
+
+/// Replace the body of error responses which were not produced by the
+/// handlers, for example by the router, with a Problem Details document.
+pub fn problem_details<B>(
+    response: ServiceResponse<B>,
+) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let handled = response
+        .response()
+        .error()
+        .map(|e| e.as_error::<ApiError>().is_some())
+        .unwrap_or(false);
+
+    if handled {
+        return Ok(ErrorHandlerResponse::Response(
+            response.map_into_left_body(),
+        ));
+    }
+
+    let status = response.status();
+    let error = ApiError::new(
+        ErrorCode::from_status(status),
+        status.canonical_reason().unwrap_or("Unknown Error"),
+    );
+    let (request, _) = response.into_parts();
+    let response: ServiceResponse<EitherBody<B, BoxBody>> =
+        ServiceResponse::new(request, error.error_response()).map_into_right_body();
+
+    Ok(ErrorHandlerResponse::Response(response))
+}
+
 //pub fn page_400() -> HandlerResult {
 //    trace!("400 Triggered!");
 //    error_400()
@@ -65,9 +165,7 @@ pub async fn api(path: Path<String>) -> Result<NamedFile, Error> {
 
     match NamedFile::open(format!("static/api/{}", path).as_str()) {
         Ok(o) => Ok(o),
-        Err(_) => {
-            Ok(NamedFile::open("static/errors/404.html")?.set_status_code(StatusCode::NOT_FOUND))
-        }
+        Err(_) => missing_file(),
     }
 }
 
@@ -76,9 +174,7 @@ pub async fn static_file(path: Path<String>) -> Result<NamedFile, Error> {
 
     match NamedFile::open(format!("static/{}", path).as_str()) {
         Ok(o) => Ok(o),
-        Err(_) => {
-            Ok(NamedFile::open("static/errors/404.html")?.set_status_code(StatusCode::NOT_FOUND))
-        }
+        Err(_) => missing_file(),
     }
 }
 
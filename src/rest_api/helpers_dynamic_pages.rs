@@ -1,23 +1,11 @@
 #![cfg(not(feature = "static-error-pages"))]
 
-use actix_web::http::StatusCode;
-use actix_web::Either;
-use actix_web::HttpResponse;
+use std::io::Error;
+use std::io::ErrorKind;
 
-use super::HandlerResult;
+use super::NamedFile;
 
-fn error(code: StatusCode) -> HandlerResult {
-    Ok(Either::Left(HttpResponse::build(code).finish()))
+/// Answer requests for missing static files as any other missing resource.
+pub fn missing_file() -> Result<NamedFile, Error> {
+    Err(Error::new(ErrorKind::NotFound, "File not found"))
 }
-
-pub fn error_400() -> HandlerResult {
-    error(StatusCode::BAD_REQUEST)
-}
-
-pub fn error_404() -> HandlerResult {
-    error(StatusCode::NOT_FOUND)
-}
-
-//pub fn error_405() -> HandlerResult {
-//    error(StatusCode::METHOD_NOT_ALLOWED)
-//}
@@ -1,25 +1,13 @@
 #![cfg(feature = "static-error-pages")]
 
-use actix_files::NamedFile;
-use actix_web::http::StatusCode;
-use actix_web::Either;
+use std::io::Error;
 
-use super::HandlerResult;
+use super::NamedFile;
+use super::StatusCode;
 
-fn error(code: StatusCode) -> HandlerResult {
-    let path = format!("static/errors/{}.html", u16::from(code));
-
-    Ok(Either::Right(NamedFile::open(path)?.set_status_code(code)))
-}
-
-pub fn error_400() -> HandlerResult {
-    error(StatusCode::BAD_REQUEST)
+/// Answer requests for missing static files with the HTML page of
+/// `static/errors`, for browsers. API errors are always Problem Details
+/// documents.
+pub fn missing_file() -> Result<NamedFile, Error> {
+    Ok(NamedFile::open("static/errors/404.html")?.set_status_code(StatusCode::NOT_FOUND))
 }
-
-pub fn error_404() -> HandlerResult {
-    error(StatusCode::NOT_FOUND)
-}
-
-//pub fn error_405() -> HandlerResult {
-//    error(StatusCode::METHOD_NOT_ALLOWED)
-//}
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Method;
+use actix_web::HttpMessage;
+use actix_web::HttpRequest;
+
+use super::auth;
+use super::web::Data;
+use super::Access;
+use super::ApiError;
+use super::ErrorCode;
+
+// Above this number of clients, the buckets which are full again are
+// forgotten.
+const MAX_BUCKETS: usize = 10_000;
+
+// Token bucket of a client.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token buckets of the clients, refilled at the same rate.
+#[derive(Debug, Default)]
+struct Buckets {
+    rate: f64,  // Tokens added per second to each bucket
+    burst: f64, // Capacity of the buckets
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Buckets {
+    fn new(rate: f64, burst: f64) -> Self {
+        Buckets {
+            rate,
+            // The bucket must hold at least one request.
+            burst: if burst > 0.0 { burst } else { rate.max(1.0) },
+            ..Default::default()
+        }
+    }
+
+    // Refill the bucket of `client`, then consume a token from it if `consume`
+    // is set. Fails when the bucket is empty.
+    fn spend(&self, client: &str, consume: bool) -> Result<(), ApiError> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|e| panic!("Can't acquire lock of the rate limits: {}", e));
+
+        if buckets.len() > MAX_BUCKETS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            if consume {
+                bucket.tokens -= 1.0;
+            }
+            Ok(())
+        } else {
+            let wait = ((1.0 - bucket.tokens) / self.rate).ceil() as u64;
+            trace!("Rate limit exceeded by '{}'", client);
+            Err(ApiError::new(ErrorCode::TooManyRequests, "Rate limit exceeded")
+                .with_retry_after(wait.max(1)))
+        }
+    }
+}
+
+/// Requests allowed per client, failed authentications allowed per address,
+/// and concurrent heavy queries allowed overall.
+///
+/// Limits are read from `MERCATOR_RATE_LIMIT`, `MERCATOR_RATE_BURST`,
+/// `MERCATOR_AUTH_FAILURES` and `MERCATOR_HEAVY_QUERIES`, 0 disabling the
+/// corresponding limit.
+#[derive(Debug, Default)]
+pub struct Limits {
+    requests: Buckets,
+    failures: Buckets, // Failed authentications, per address
+    heavy: usize,
+    running: Arc<AtomicUsize>,
+}
+
+impl Limits {
+    pub fn new(rate: f64, burst: f64, failures: usize, heavy: usize) -> Self {
+        Limits {
+            requests: Buckets::new(rate, burst),
+            // The failures allowed per minute may all happen at once.
+            failures: Buckets::new(failures as f64 / 60.0, failures as f64),
+            heavy,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).unwrap_or_default();
+
+        Limits::new(
+            var("MERCATOR_RATE_LIMIT").parse().unwrap_or(0.0),
+            var("MERCATOR_RATE_BURST").parse().unwrap_or(0.0),
+            var("MERCATOR_AUTH_FAILURES").parse().unwrap_or(0),
+            var("MERCATOR_HEAVY_QUERIES").parse().unwrap_or(0),
+        )
+    }
+
+    // Consume a token from the bucket of `client`.
+    fn take(&self, client: &str) -> Result<(), ApiError> {
+        self.requests.spend(client, true)
+    }
+
+    // Reserve a slot for a heavy query, released when the permit is dropped.
+    fn acquire(&self) -> Result<Option<Permit>, ApiError> {
+        if self.heavy == 0 {
+            return Ok(None);
+        }
+
+        // The slot is released right away if the limit is exceeded, when the
+        // permit is dropped.
+        let running = self.running.fetch_add(1, Ordering::SeqCst);
+        let permit = Permit {
+            running: self.running.clone(),
+        };
+        if running < self.heavy {
+            Ok(Some(permit))
+        } else {
+            trace!("Too many concurrent heavy queries: {}", running);
+            Err(ApiError::new(
+                ErrorCode::TooManyRequests,
+                "Too many concurrent queries",
+            )
+            .with_retry_after(1))
+        }
+    }
+}
+
+/// Slot of a heavy query, held until the response is complete.
+///
+/// It is stored in the extensions of the request, where handlers sending
+/// their response in the background may take it from with `take_permit`.
+#[derive(Debug)]
+pub struct Permit {
+    running: Arc<AtomicUsize>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Take the slot of the heavy query `request`, if any, to hold it until its
+/// response is complete. Otherwise it is released once the handler returns.
+pub fn take_permit(request: &HttpRequest) -> Option<Permit> {
+    request.extensions_mut().remove::<Permit>()
+}
+
+// Requests which may execute a filter or a query.
+fn heavy(request: &ServiceRequest) -> bool {
+    request.method() == Method::POST && !request.path().contains("/admin/")
+}
+
+// Address of the client of `request`.
+fn address(request: &ServiceRequest) -> String {
+    request
+        .peer_addr()
+        .map(|address| address.ip().to_string())
+        .unwrap_or_default()
+}
+
+/// Authenticate `request`, see `auth::authenticate`.
+///
+/// Addresses which failed to authenticate too often are refused until their
+/// allowance is restored, whichever the credentials presented.
+pub fn authenticate(request: &ServiceRequest) -> Result<Access, ApiError> {
+    let limits = match request.app_data::<Data<Limits>>() {
+        Some(limits) if !auth::public(request.path()) => limits,
+        _ => return auth::authenticate(request),
+    };
+
+    let address = address(request);
+    limits.failures.spend(&address, false)?;
+
+    auth::authenticate(request).map_err(|e| {
+        if e.code() == ErrorCode::Unauthorized {
+            warn!(target: "audit", "Failed authentication from '{}'", address);
+            // The allowance was checked above, so this only consumes it.
+            let _ = limits.failures.spend(&address, true);
+        }
+        e
+    })
+}
+
+/// Apply the limits registered as application data to `request`, on behalf
+/// of the client authenticated as `access`. The slot of heavy queries is
+/// stored in the extensions of the request.
+///
+/// Clients are identified by their token, or their address for anonymous
+/// requests. Public endpoints are not limited.
+pub fn admit(request: &ServiceRequest, access: &Access) -> Result<(), ApiError> {
+    let limits = match request.app_data::<Data<Limits>>() {
+        None => return Ok(()),
+        Some(limits) => limits,
+    };
+
+    if auth::public(request.path()) {
+        return Ok(());
+    }
+
+    let client = match access.client() {
+        Some(client) => client.to_string(),
+        None => address(request),
+    };
+    limits.take(&client)?;
+
+    if heavy(request) {
+        if let Some(permit) = limits.acquire()? {
+            request.extensions_mut().insert(permit);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let limits = Limits::new(1.0, 2.0, 0, 0);
+
+        assert!(limits.take("a").is_ok());
+        assert!(limits.take("a").is_ok());
+        assert!(limits.take("b").is_ok());
+
+        let error = limits.take("a").unwrap_err();
+        assert_eq!(error.code(), ErrorCode::TooManyRequests);
+        assert_eq!(error.retry_after(), Some(1));
+    }
+
+    #[test]
+    fn permits() {
+        let limits = Limits::new(0.0, 0.0, 0, 1);
+
+        let permit = limits.acquire().unwrap();
+        assert!(permit.is_some());
+        assert!(limits.acquire().is_err());
+
+        drop(permit);
+        assert!(limits.acquire().unwrap().is_some());
+    }
+
+    #[test]
+    fn failures() {
+        let limits = Limits::new(0.0, 0.0, 2, 0);
+
+        assert!(limits.failures.spend("a", false).is_ok());
+        assert!(limits.failures.spend("a", true).is_ok());
+        assert!(limits.failures.spend("a", true).is_ok());
+
+        let error = limits.failures.spend("a", false).unwrap_err();
+        assert_eq!(error.code(), ErrorCode::TooManyRequests);
+        assert!(limits.failures.spend("b", false).is_ok());
+
+        // Requests are not limited meanwhile.
+        assert!(limits.take("a").is_ok());
+    }
+}
+
+#[cfg(test)]
+mod routing {
+    use super::super::tests_utils::*;
+    use super::super::Tokens;
+    use super::Limits;
+    use actix_web::http::header;
+    use actix_web::http::StatusCode;
+
+    #[actix_web::test]
+    async fn rate() {
+        let replies = call(
+            Tokens::default(),
+            Some(Limits::new(1.0, 1.0, 0, 0)),
+            vec![
+                TestRequest::get().uri(&get_core(CORE)),
+                TestRequest::get().uri(&get_core(CORE)),
+                TestRequest::get().uri(&get_path("/health")),
+            ],
+        )
+        .await;
+
+        assert_eq!(replies[0].status, StatusCode::OK);
+        assert_eq!(replies[1].status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(replies[1].headers.contains_key(header::RETRY_AFTER));
+
+        // Public endpoints are not limited.
+        assert_eq!(replies[2].status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn failures() {
+        let file = std::env::temp_dir().join("mercator-tokens-failures.json");
+        std::fs::write(&file, r#"[{"name": "reader", "token": "secret-read"}]"#).unwrap();
+        let tokens = Tokens::load(file.to_str().unwrap()).unwrap();
+
+        let request = |token: &'static str| {
+            TestRequest::get()
+                .uri(&get_core(CORE))
+                .insert_header(("X-API-Key", token))
+        };
+        let replies = call(
+            tokens,
+            Some(Limits::new(0.0, 0.0, 1, 0)),
+            vec![
+                request("secret-guess"),
+                request("secret-read"),
+                TestRequest::get().uri(&get_path("/health")),
+            ],
+        )
+        .await;
+
+        assert_eq!(replies[0].status, StatusCode::UNAUTHORIZED);
+
+        // Valid credentials are refused as well, until the allowance is
+        // restored.
+        assert_eq!(replies[1].status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(replies[1].headers.contains_key(header::RETRY_AFTER));
+
+        // Public endpoints are not limited.
+        assert_eq!(replies[2].status, StatusCode::OK);
+    }
+}
@@ -1,31 +1,43 @@
 mod actions;
+mod admin;
+mod aggregate;
+mod auth;
 
 mod space;
 mod spaces;
 
 mod core;
+mod core_density;
+mod core_index;
 mod cores;
 
 mod spatial_object;
 mod spatial_objects;
 
+mod encoding;
+mod error;
+mod export;
 mod helpers;
 mod helpers_dynamic_pages;
 mod helpers_static_pages;
+mod limits;
+mod ndjson;
 
-use std::io::Error;
+use std::collections::HashMap;
 use std::process::exit;
 use std::sync::RwLock;
+use std::time::Duration;
 
 use actix_cors::Cors;
 use actix_files::NamedFile;
+use actix_web::dev::Service;
 use actix_web::http;
 use actix_web::http::StatusCode;
 use actix_web::middleware;
 use actix_web::web;
 pub use actix_web::web::Data;
 use actix_web::App;
-use actix_web::Either;
+use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::HttpServer;
 use mercator_db::space::Shape;
@@ -38,9 +50,22 @@ use mercator_db::Properties;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::shared_state::Graduations;
+use crate::shared_state::Shapes;
+use crate::shared_state::SpaceSummary;
+use crate::shared_state::Truncation;
 use crate::SharedState;
 
+pub use aggregate::Aggregation;
+pub use auth::Access;
+pub use auth::Role;
+pub use auth::Tokens;
+pub use encoding::Encoding;
+pub use error::ApiError;
+pub use error::ErrorCode;
+pub use export::Format;
 pub use helpers::*;
+pub use limits::Limits;
 
 #[cfg(not(feature = "static-error-pages"))]
 pub use helpers_dynamic_pages::*;
@@ -48,7 +73,7 @@ pub use helpers_dynamic_pages::*;
 #[cfg(feature = "static-error-pages")]
 pub use helpers_static_pages::*;
 
-pub type HandlerResult = Result<Either<HttpResponse, NamedFile>, Error>;
+pub type HandlerResult = Result<HttpResponse, ApiError>;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Filters {
@@ -57,6 +82,56 @@ pub struct Filters {
     space: Option<String>, // Output space, None, means each object in its own original space
     resolution: Option<Vec<u32>>, // None means automatic selection, based on ViewPort
     view_port: Option<(Vec<f64>, Vec<f64>)>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    cursor: Option<String>, // Opaque token, as returned in `Page::next`
+    export: Option<Format>,
+    aggregate: Option<Aggregation>,
+    timeout_ms: Option<u64>, // Capped by `MERCATOR_QUERY_TIMEOUT`
+}
+
+// Advice given along with truncated results.
+const TRUNCATED_HINT: &str =
+    "Results exceed the size limits of the service, narrow the view_port or coarsen the resolution";
+
+/// One page of a paginated listing, ordered by object id, or a listing
+/// truncated to the size limits of the service.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    objects: Vec<T>,
+    next: Option<String>,
+    truncated: bool,
+    hint: Option<&'static str>, // Set when truncated
+}
+
+impl<T> Page<T> {
+    pub fn new(objects: Vec<T>, next: Option<String>, truncated: bool) -> Self {
+        Page {
+            objects,
+            next,
+            truncated,
+            hint: if truncated { Some(TRUNCATED_HINT) } else { None },
+        }
+    }
+}
+
+// Cursors are the hex-encoded id of the last object of the previous page, so
+// that they remain valid when objects are added or removed between requests.
+fn encode_cursor(id: &str) -> String {
+    id.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Option<String> {
+    if cursor.len() % 2 != 0 {
+        return None;
+    }
+
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| cursor.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect::<Option<Vec<_>>>()?;
+
+    String::from_utf8(bytes).ok()
 }
 
 impl Filters {
@@ -65,16 +140,25 @@ impl Filters {
     }
 
     pub fn ids_only(&self) -> bool {
-        self.ids_only.unwrap_or(true)
+        // Exports are made of whole objects.
+        self.ids_only.unwrap_or(self.export.is_none())
+    }
+
+    pub fn export(&self) -> Option<Format> {
+        self.export
+    }
+
+    pub fn aggregate(&self) -> Option<Aggregation> {
+        self.aggregate
     }
 
     pub fn space(&self, db: &DataBase) -> Result<&Option<String>, HandlerResult> {
         if let Some(space_id) = &self.space {
             if !db.space_keys().contains(&space_id.to_string()) {
-                return Err(error_422(format!(
-                    "Invalid reference space id in '{:?}'",
-                    self
-                )));
+                return Err(error_422_field(
+                    "space",
+                    format!("Invalid reference space id in '{:?}'", self),
+                ));
             }
         }
         Ok(&self.space)
@@ -89,24 +173,231 @@ impl Filters {
             Shape::BoundingBox(low.into(), high.into()).volume()
         )
     }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn paginated(&self) -> bool {
+        self.limit.is_some() || self.offset.is_some() || self.cursor.is_some()
+    }
+
+    /// Sort `items` by `key`, and select the page requested, as well as the
+    /// cursor to the following one, if any.
+    ///
+    /// Pages are cut short to the size limits of `truncation`, the cursor
+    /// then leading to the objects left out.
+    pub fn page<T, K>(
+        &self,
+        mut items: Vec<T>,
+        key: K,
+        truncation: &Truncation,
+    ) -> Result<(Vec<T>, Option<String>), HandlerResult>
+    where
+        K: Fn(&T) -> &str,
+    {
+        items.sort_by(|a, b| key(a).cmp(key(b)));
+
+        let start = match &self.cursor {
+            None => 0,
+            Some(cursor) => match decode_cursor(cursor) {
+                None => {
+                    return Err(error_422_field(
+                        "cursor",
+                        format!("Invalid cursor '{}'", cursor),
+                    ))
+                }
+                Some(last) => items.partition_point(|item| key(item) <= last.as_str()),
+            },
+        };
+        let start = (start + self.offset.unwrap_or(0)).min(items.len());
+        let end = match self.limit {
+            None => items.len(),
+            Some(limit) => (start + limit).min(items.len()),
+        };
+        let end = start + truncation.limit(start..end).count();
+
+        let next = if end < items.len() && end > start {
+            Some(encode_cursor(key(&items[end - 1])))
+        } else {
+            None
+        };
+
+        items.truncate(end);
+        items.drain(..start);
+
+        Ok((items, next))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SpatialObjectPartial {
+    id: Option<String>, // Taken from the path when updating a single object
+    #[serde(rename = "type")]
+    type_name: Option<String>,
+    add: Option<Vec<model::v2::Volume>>,
+    remove: Option<Vec<model::v2::Volume>>,
+}
+
+impl SpatialObjectPartial {
+    pub fn id(&self) -> &Option<String> {
+        &self.id
+    }
+
+    pub fn volumes(&self) -> impl Iterator<Item = &model::v2::Volume> {
+        self.add.iter().chain(self.remove.iter()).flatten()
+    }
+
+    /// Apply the patch to `object`. Stored positions are decoded from the
+    /// index, so removed shapes are matched up to the precision of the
+    /// `graduations` of their reference space.
+    ///
+    /// Returns whether the object was modified.
+    pub fn apply(
+        &self,
+        object: &mut model::v2::SpatialObject,
+        graduations: &HashMap<String, Graduations>,
+    ) -> bool {
+        let mut modified = false;
+
+        if let Some(type_name) = &self.type_name {
+            if &object.properties.type_name != type_name {
+                object.properties.type_name = type_name.clone();
+                modified = true;
+            }
+        }
+
+        if let Some(volumes) = &self.add {
+            for added in volumes.iter().filter(|v| !v.shapes.is_empty()) {
+                match object.volumes.iter_mut().find(|v| v.space == added.space) {
+                    Some(volume) => volume.shapes.extend(added.shapes.iter().cloned()),
+                    None => object.volumes.push(added.clone()),
+                }
+                modified = true;
+            }
+        }
+
+        if let Some(volumes) = &self.remove {
+            for removed in volumes {
+                let graduations = match graduations.get(&removed.space) {
+                    None => continue,
+                    Some(graduations) => graduations,
+                };
+                let volume = match object.volumes.iter_mut().find(|v| v.space == removed.space) {
+                    None => continue,
+                    Some(volume) => volume,
+                };
+                let removed = removed
+                    .shapes
+                    .iter()
+                    .filter_map(|shape| Shapes::from_model(shape).ok())
+                    .collect::<Vec<_>>();
+                let contained = |position: &Vec<f64>| {
+                    removed
+                        .iter()
+                        .any(|shapes| shapes.contains(position, graduations))
+                };
+
+                let mut shapes = Vec::with_capacity(volume.shapes.len());
+                for shape in volume.shapes.drain(..) {
+                    let points = match Shapes::from_model(&shape) {
+                        Ok(Shapes::Points(points)) => points,
+                        _ => {
+                            shapes.push(shape);
+                            continue;
+                        }
+                    };
+
+                    if !points.iter().any(contained) {
+                        shapes.push(shape);
+                        continue;
+                    }
+
+                    modified = true;
+                    let kept = points
+                        .into_iter()
+                        .filter(|point| !contained(point))
+                        .collect::<Vec<_>>();
+                    if !kept.is_empty() {
+                        if let Ok(shape) = Shapes::Points(kept).to_model() {
+                            shapes.push(shape);
+                        }
+                    }
+                }
+                volume.shapes = shapes;
+            }
+            object.volumes.retain(|volume| !volume.shapes.is_empty());
+        }
+
+        modified
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Core {
     name: String,
     version: String,
-    scales: Vec<Vec<i32>>,
+    scales: Vec<Vec<i32>>, // Empty means automatic selection
+    #[serde(default, skip_deserializing)]
+    spaces: Vec<SpaceSummary>,
 }
 
-impl From<&mercator_db::Core> for Core {
-    fn from(core: &mercator_db::Core) -> Self {
+impl Core {
+    /// Describe `core`, including a summary of its content per reference
+    /// space.
+    pub fn describe(context: &SharedState, core: &mercator_db::Core) -> Result<Self, String> {
+        Ok(Core {
+            spaces: context.summary(core.name())?,
+            ..Core::identify(context, core)
+        })
+    }
+
+    /// Describe `core` without summarizing its content, which requires a
+    /// full scan of the core. `spaces` is left empty.
+    pub fn identify(context: &SharedState, core: &mercator_db::Core) -> Self {
+        let scales = context
+            .scales(core.name())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|scale| scale.into_iter().map(|v| v as i32).collect())
+            .collect();
+
         Core {
             name: core.name().clone(),
             version: core.version().clone(),
-            scales: vec![vec![0, 0, 0]],
-            // FIXME: Report the actual values. Might need to change the format
-            //        to per reference space.
+            scales,
+            spaces: vec![],
+        }
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn version(&self) -> &String {
+        &self.version
+    }
+
+    /// Scales to use to build the index, an empty list means automatic
+    /// selection.
+    pub fn scales(&self) -> Result<Option<Vec<Vec<u32>>>, String> {
+        if self.scales.is_empty() {
+            return Ok(None);
+        }
+
+        let mut scales = Vec::with_capacity(self.scales.len());
+        for scale in &self.scales {
+            let mut values = Vec::with_capacity(scale.len());
+            for value in scale {
+                if *value < 0 {
+                    return Err(format!("Invalid negative scale in '{:?}'", self.scales));
+                }
+                values.push(*value as u32);
+            }
+            scales.push(values);
         }
+
+        Ok(Some(scales))
     }
 }
 
@@ -125,6 +416,8 @@ fn config_v1(cfg: &mut web::ServiceConfig) {
     space::config(cfg);
     spaces::config(cfg);
 
+    core_density::config(cfg);
+    core_index::config(cfg);
     core::config(cfg);
     cores::config(cfg);
 
@@ -132,6 +425,7 @@ fn config_v1(cfg: &mut web::ServiceConfig) {
     spatial_objects::config(cfg);
 
     actions::config(cfg);
+    admin::config(cfg);
 
     cfg.route("/static/{file:.*}", web::get().to(static_file));
     cfg.route("/api/{file:.*}", web::get().to(api));
@@ -175,9 +469,11 @@ pub fn get_cors() -> Cors {
         }
     }
 
-    cors.allowed_methods(vec!["GET", "POST", "UPDATE", "PATCH", "DELETE", "OPTIONS"])
+    cors.allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"])
         .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
         .allowed_header(http::header::CONTENT_TYPE)
+        .allowed_header(http::header::HeaderName::from_static("x-api-key"))
+        .expose_headers(vec!["server-timing", export::TRUNCATED])
         .max_age(600)
 }
 
@@ -185,6 +481,29 @@ macro_rules! get_app {
     ($state:expr) => {
         App::new()
             .app_data($state.clone())
+            .app_data(web::JsonConfig::default().error_handler(|e, _| bad_request(e)))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| bad_request(e)))
+            .app_data(web::PathConfig::default().error_handler(|e, _| bad_request(e)))
+            .wrap(middleware::ErrorHandlers::new().default_handler(problem_details))
+            .wrap_fn(|request, service| {
+                let call = limits::authenticate(&request)
+                    .and_then(|access| limits::admit(&request, &access).map(|_| access))
+                    .map(|access| access.scope(service.call(request)));
+                async move {
+                    match call {
+                        Err(e) => Err(e.into()),
+                        Ok(call) => call.await.map(|response| {
+                            // Release the slot of the heavy query, unless the
+                            // handler took it to send its response.
+                            limits::take_permit(response.request());
+                            response
+                        }),
+                    }
+                }
+            })
+            .wrap_fn(|request, service| {
+                Encoding::negotiate(request.headers()).scope(service.call(request))
+            })
             .wrap(middleware::Logger::new(
                 r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T[s] %D[ms]"#,
             ))
@@ -198,8 +517,22 @@ macro_rules! get_app {
 pub async fn run(host: &str, port: u16, state: Data<RwLock<SharedState>>) -> std::io::Result<()> {
     info!("Starting http server: {}:{}", host, port);
 
+    let tokens = match Tokens::from_env() {
+        Ok(tokens) => Data::new(tokens),
+        Err(e) => {
+            error!("Could not load {} : `{}`", "MERCATOR_TOKENS", e);
+            exit(1);
+        }
+    };
+
+    let limits = Data::new(Limits::from_env());
+
     // Create & run the server.
-    HttpServer::new(move || get_app!(state))
+    HttpServer::new(move || {
+        get_app!(state)
+            .app_data(tokens.clone())
+            .app_data(limits.clone())
+    })
         .bind(format!("{}:{}", host, port))?
         .run()
         .await
@@ -242,8 +575,10 @@ mod tests_utils {
             {
                 std::env::set_var("MERCATOR_BASE", PREFIX);
                 let db = DataBase::load(&[CORE_FILE]).unwrap();
+                // Anonymous clients may modify the test database.
                 let app = test::init_service(
-                    get_app!(Data::new(RwLock::new(SharedState::new(db))))).await;
+                    get_app!(Data::new(RwLock::new(SharedState::new(db, vec![]))))
+                        .app_data(Data::new(Tokens::anonymous(Role::Write)))).await;
                 let request = $request.uri(&$path).to_request();
                 let response = test::call_service(&app, request).await;
                 assert_eq!(response.status(), $code);
@@ -253,16 +588,89 @@ mod tests_utils {
         };
     }
 
+    /// Send `requests` in order to a single instance of the service, check
+    /// their status codes, and retrieve the bodies of the responses.
+    pub async fn expect_sequence(requests: Vec<(TestRequest, String, u16)>) -> Vec<web::Bytes> {
+        std::env::set_var("MERCATOR_BASE", PREFIX);
+        let db = DataBase::load(&[CORE_FILE]).unwrap();
+        let app = test::init_service(
+            get_app!(Data::new(RwLock::new(SharedState::new(db, vec![]))))
+                .app_data(Data::new(Tokens::anonymous(Role::Write))),
+        )
+        .await;
+
+        let mut bodies = vec![];
+        for (request, path, code) in requests {
+            let response = test::call_service(&app, request.uri(&path).to_request()).await;
+            assert_eq!(response.status().as_u16(), code, "{}", path);
+            bodies.push(test::read_body(response).await);
+        }
+
+        bodies
+    }
+
+    /// Status, headers and body of a response.
+    pub struct Reply {
+        pub status: StatusCode,
+        pub headers: http::header::HeaderMap,
+        pub body: web::Bytes,
+    }
+
+    /// Send `requests` in order to a single instance of the service, which
+    /// accepts `tokens` and enforces `limits`, if any.
+    pub async fn call(
+        tokens: Tokens,
+        limits: Option<Limits>,
+        requests: Vec<TestRequest>,
+    ) -> Vec<Reply> {
+        std::env::set_var("MERCATOR_BASE", PREFIX);
+        let db = DataBase::load(&[CORE_FILE]).unwrap();
+        let app = get_app!(Data::new(RwLock::new(SharedState::new(db, vec![]))))
+            .app_data(Data::new(tokens));
+        let app = match limits {
+            None => app,
+            Some(limits) => app.app_data(Data::new(limits)),
+        };
+        let app = test::init_service(app).await;
+
+        let mut replies = vec![];
+        for request in requests {
+            let response = test::call_service(&app, request.to_request()).await;
+            replies.push(Reply {
+                status: response.status(),
+                headers: response.headers().clone(),
+                body: test::read_body(response).await,
+            });
+        }
+
+        replies
+    }
+
     /// Checks status code OK
     pub async fn expect_200(method: TestRequest, path: &str) {
         expect_code!(method, path, StatusCode::OK);
     }
 
+    /// Checks status code ACCEPTED
+    pub async fn expect_202(method: TestRequest, path: &str) {
+        expect_code!(method, path, StatusCode::ACCEPTED);
+    }
+
     /// Checks status code BAD_REQUEST
     pub async fn expect_400(method: TestRequest, path: &str) {
         expect_code!(method, path, StatusCode::BAD_REQUEST);
     }
 
+    /// Checks status code UNAUTHORIZED
+    pub async fn expect_401(method: TestRequest, path: &str) {
+        expect_code!(method, path, StatusCode::UNAUTHORIZED);
+    }
+
+    /// Checks status code FORBIDDEN
+    pub async fn expect_403(method: TestRequest, path: &str) {
+        expect_code!(method, path, StatusCode::FORBIDDEN);
+    }
+
     /// Checks status code NOT_FOUND
     pub async fn expect_404(method: TestRequest, path: &str) {
         expect_code!(method, path, StatusCode::NOT_FOUND);
@@ -273,11 +681,21 @@ mod tests_utils {
         expect_code!(method, path, StatusCode::METHOD_NOT_ALLOWED);
     }
 
+    /// Checks status code CONFLICT
+    pub async fn expect_409(method: TestRequest, path: &str) {
+        expect_code!(method, path, StatusCode::CONFLICT);
+    }
+
     /// Checks status code UNPROCESSABLE_ENTITY
     pub async fn expect_422(method: TestRequest, path: &str) {
         expect_code!(method, path, StatusCode::UNPROCESSABLE_ENTITY);
     }
 
+    /// Checks status code GATEWAY_TIMEOUT
+    pub async fn expect_504(method: TestRequest, path: &str) {
+        expect_code!(method, path, StatusCode::GATEWAY_TIMEOUT);
+    }
+
     pub mod json {
         use super::*;
 
@@ -0,0 +1,264 @@
+use std::io::Error;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::sync::RwLock;
+use std::task::Context;
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use futures::channel::mpsc;
+use futures::channel::oneshot;
+use futures::task;
+use futures::task::ArcWake;
+use rayon::ThreadPool;
+use rayon::ThreadPoolBuilder;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::error_500;
+use super::limits;
+use super::limits::Permit;
+use super::web::Data;
+use super::web::Query;
+use super::ApiError;
+use super::HandlerResult;
+use super::HttpRequest;
+use super::HttpResponse;
+use super::SharedState;
+
+pub const CONTENT_TYPE: &str = "application/x-ndjson";
+
+// Number of lines serialized ahead of the client, bounding the memory used
+// by a stream regardless of the size of the result set.
+const BUFFERED_LINES: usize = 64;
+
+/// Thread pool producing the streamed responses, additional streams wait for
+/// a thread to be available.
+///
+/// Its size is set by `MERCATOR_STREAM_THREADS`, 0 meaning one thread per
+/// logical CPU.
+fn stream_pool() -> &'static ThreadPool {
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+    POOL.get_or_init(|| {
+        let threads = std::env::var("MERCATOR_STREAM_THREADS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("mercator-stream-{}", i))
+            .build()
+            .unwrap_or_else(|e| panic!("Can't create the stream thread pool: {}", e))
+    })
+}
+
+/// Time given to the client to read the lines buffered ahead of it, after
+/// which it is considered gone.
+///
+/// It is set by `MERCATOR_STREAM_TIMEOUT`, in seconds.
+fn stream_timeout() -> Duration {
+    static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+    *TIMEOUT.get_or_init(|| {
+        let seconds = std::env::var("MERCATOR_STREAM_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(30);
+
+        Duration::from_secs(seconds.max(1))
+    })
+}
+
+// Wakes up the producer waiting for room in the buffer of the stream.
+struct Unpark(thread::Thread);
+
+impl ArcWake for Unpark {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.unpark();
+    }
+}
+
+// Send `line` through `sender`, waiting at most `timeout` for room in the
+// buffer. Returns false when the line could not be sent.
+fn send_timeout(
+    sender: &mut mpsc::Sender<Result<Bytes, Error>>,
+    line: Bytes,
+    timeout: Duration,
+) -> bool {
+    let deadline = Instant::now() + timeout;
+    let waker = task::waker(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        match sender.poll_ready(&mut cx) {
+            Poll::Ready(Err(_)) => return false,
+            Poll::Ready(Ok(())) => return sender.start_send(Ok(line)).is_ok(),
+            Poll::Pending => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return false;
+                }
+                // Wake ups may be spurious, the buffer is checked again.
+                thread::park_timeout(deadline - now);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamParameters {
+    stream: Option<bool>,
+}
+
+/// Whether the client asked for a stream, either through the `Accept` header
+/// or with `?stream=true`.
+pub fn requested(request: &HttpRequest) -> bool {
+    let accepted = request
+        .headers()
+        .get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| media.split(';').next().map(str::trim) == Some(CONTENT_TYPE));
+
+    accepted
+        || Query::<StreamParameters>::from_query(request.query_string())
+            .map(|parameters| parameters.stream.unwrap_or(false))
+            .unwrap_or(false)
+}
+
+/// Sink for the lines of a stream.
+///
+/// Clones send to the same stream, so items may be produced concurrently.
+#[derive(Clone)]
+pub struct Lines {
+    // Taken by the first line sent, or once the producer returns.
+    started: Arc<Mutex<Option<oneshot::Sender<Result<(), ApiError>>>>>,
+    sender: mpsc::Sender<Result<Bytes, Error>>,
+    // Set once the client disconnected, or stopped reading.
+    gone: Arc<AtomicBool>,
+    // Slot of the heavy query, released as soon as the client is gone.
+    permit: Arc<Mutex<Option<Permit>>>,
+}
+
+impl Lines {
+    fn started(&self) -> Option<oneshot::Sender<Result<(), ApiError>>> {
+        self.started
+            .lock()
+            .unwrap_or_else(|e| panic!("Can't acquire lock of the stream: {}", e))
+            .take()
+    }
+
+    fn start(&self) {
+        if let Some(started) = self.started() {
+            // The handler only goes away with the client, send() will notice.
+            let _ = started.send(Ok(()));
+        }
+    }
+
+    // Give up on the client, for every clone of the stream.
+    fn close(&self) {
+        self.gone.store(true, Ordering::SeqCst);
+        self.permit
+            .lock()
+            .unwrap_or_else(|e| panic!("Can't acquire lock of the stream: {}", e))
+            .take();
+    }
+
+    /// Serialize `item` on its own line. Returns false once the client is
+    /// gone, in which case the producer should stop.
+    ///
+    /// Clients which do not read the lines buffered ahead of them within
+    /// `MERCATOR_STREAM_TIMEOUT` are considered gone as well.
+    pub fn send<T>(&mut self, item: &T) -> bool
+    where
+        T: Serialize,
+    {
+        if self.gone.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        let mut line = match serde_json::to_vec(item) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Can't serialize streamed item: {}", e);
+                return false;
+            }
+        };
+        line.push(b'\n');
+
+        self.start();
+        if send_timeout(&mut self.sender, Bytes::from(line), stream_timeout()) {
+            return true;
+        }
+
+        warn!("Stream client gone, or not reading, stopping");
+        self.close();
+        false
+    }
+}
+
+/// Run `produce` on the stream thread pool, and stream the lines it sends as
+/// they come.
+///
+/// Clients may read the lines at their own pace, so `produce` works on a
+/// snapshot of the database instead of holding its lock meanwhile.
+///
+/// The slot of the heavy query `request`, if any, is held until the last line
+/// is produced, or the client is gone.
+///
+/// Errors returned before the first line is sent are reported as usual, with
+/// the matching status code. Later errors are appended as a final line holding
+/// the Problem Details document.
+pub async fn stream<F>(
+    request: &HttpRequest,
+    state: Data<RwLock<SharedState>>,
+    produce: F,
+) -> HandlerResult
+where
+    F: FnOnce(&SharedState, &mut Lines) -> Result<(), ApiError> + Send + 'static,
+{
+    let (started, start) = oneshot::channel();
+    let (sender, body) = mpsc::channel(BUFFERED_LINES);
+
+    let context = state
+        .read()
+        .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e))
+        .snapshot();
+    let permit = limits::take_permit(request);
+
+    stream_pool().spawn(move || {
+        let mut lines = Lines {
+            started: Arc::new(Mutex::new(Some(started))),
+            sender,
+            gone: Arc::new(AtomicBool::new(false)),
+            permit: Arc::new(Mutex::new(permit)),
+        };
+
+        let result = produce(&context, &mut lines);
+        match (lines.started(), result) {
+            (Some(started), result) => {
+                let _ = started.send(result);
+            }
+            (None, Ok(())) => (),
+            (None, Err(e)) => {
+                warn!("Stream interrupted: {}", e);
+                lines.send(&e);
+            }
+        }
+    });
+
+    match start.await {
+        Err(_) => error_500("Streaming thread terminated unexpectedly"),
+        Ok(Err(e)) => Err(e),
+        Ok(Ok(())) => Ok(HttpResponse::Ok().content_type(CONTENT_TYPE).streaming(body)),
+    }
+}
@@ -1,18 +1,145 @@
 use std::sync::RwLock;
 
-use super::error_400;
+use serde::Deserialize;
+
 use super::error_404;
+use super::error_409;
+use super::error_422_field;
+use super::error_500;
 use super::model;
 use super::ok_200;
+use super::spatial_objects::MODIFY_ATTEMPTS;
 use super::web;
 use super::web::Data;
+use super::web::Json;
 use super::web::Path;
+use super::Access;
+use super::ApiError;
 use super::HandlerResult;
+use super::Role;
 use super::SharedState;
 
-async fn put(path: Path<String>) -> HandlerResult {
-    trace!("POST '{:?}'", path);
-    error_400()
+#[derive(Debug, Deserialize)]
+pub struct SpacePartial {
+    origin: Option<Vec<f64>>,
+    axes: Option<Vec<model::Axis>>,
+}
+
+impl SpacePartial {
+    pub fn apply(&self, space: &mut model::Space) {
+        if let Some(origin) = &self.origin {
+            space.origin = origin.clone();
+        }
+
+        if let Some(axes) = &self.axes {
+            space.axes = axes.clone();
+        }
+    }
+}
+
+fn check_space(space: &model::Space) -> Result<(), ApiError> {
+    if space.axes.is_empty() || space.origin.len() != space.axes.len() {
+        return error_422_field(
+            "axes",
+            format!(
+                "Origin and axes dimensions do not match in '{}'",
+                space.name
+            ),
+        )
+        .map(drop);
+    }
+
+    if let Some(axis) = space
+        .axes
+        .iter()
+        .find(|axis| axis.unit_vector.len() != space.origin.len())
+    {
+        return error_422_field(
+            "axes",
+            format!(
+                "Invalid unit vector dimension in '{}': {:?}",
+                space.name, axis.unit_vector
+            ),
+        )
+        .map(drop);
+    }
+
+    Ok(())
+}
+
+/// Replace a reference space by the definition `define` computes from the
+/// database, then rebuild the cores using it without holding the lock on
+/// the database, and install them along with the new definition.
+///
+/// `define` is applied again, to a fresh snapshot, when the database was
+/// modified in the meantime.
+async fn set_space<F>(state: Data<RwLock<SharedState>>, define: F) -> HandlerResult
+where
+    F: Fn(&SharedState) -> Result<model::Space, ApiError> + Send + 'static,
+{
+    let installed = web::block(move || {
+        for _ in 0..MODIFY_ATTEMPTS {
+            let (job, space) = {
+                let context = state
+                    .read()
+                    .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+
+                let space = define(&context)?;
+                check_space(&space)?;
+
+                match context.space_job((&space).into()) {
+                    Err(e) => return error_500(e).map(|_| unreachable!()),
+                    Ok(job) => (job, space),
+                }
+            };
+
+            // The objects already stored in this space have to remain valid.
+            if let Err(e) = job.check() {
+                return error_422_field("axes", e).map(|_| unreachable!());
+            }
+
+            // The current indices keep serving queries until the new ones
+            // are ready.
+            let cores = job.build();
+
+            let installed = state
+                .write()
+                .unwrap_or_else(|e| panic!("Can't acquire write lock of the database: {}", e))
+                .install_space(job, cores);
+
+            match installed {
+                Err(e) => return error_500(e).map(|_| unreachable!()),
+                Ok(true) => return Ok(space),
+                Ok(false) => trace!("Space '{}' modified concurrently, retrying", space.name),
+            }
+        }
+
+        error_409().map(|_| unreachable!())
+    })
+    .await;
+
+    match installed {
+        Err(e) => error_500(e),
+        Ok(space) => ok_200(&space?),
+    }
+}
+
+async fn put(
+    (path, space, state): (Path<String>, Json<model::Space>, Data<RwLock<SharedState>>),
+) -> HandlerResult {
+    trace!("PUT '{:?}'", path);
+    Access::current().require(Role::Write)?;
+    let name = path.to_string();
+
+    if space.name != name {
+        return error_422_field(
+            "name",
+            format!("Space name '{}' does not match '{}'", space.name, name),
+        );
+    }
+
+    let space = space.into_inner();
+    set_space(state, move |_| Ok(space.clone())).await
 }
 
 async fn get((path, state): (Path<String>, Data<RwLock<SharedState>>)) -> HandlerResult {
@@ -31,14 +158,53 @@ async fn get((path, state): (Path<String>, Data<RwLock<SharedState>>)) -> Handle
     }
 }
 
-async fn patch(path: Path<String>) -> HandlerResult {
-    trace!("PATCH Triggered on {}", path);
-    error_400()
+async fn patch(
+    (path, patch, state): (Path<String>, Json<SpacePartial>, Data<RwLock<SharedState>>),
+) -> HandlerResult {
+    trace!("PATCH '{:?}', {:?}", path, patch);
+    Access::current().require(Role::Write)?;
+    let name = path.to_string();
+    let patch = patch.into_inner();
+
+    set_space(state, move |context| {
+        let mut space = match context.db().space(&name) {
+            Err(_) => return error_404().map(|_| unreachable!()),
+            Ok(space) => model::Space::from(space),
+        };
+        patch.apply(&mut space);
+
+        Ok(space)
+    })
+    .await
 }
 
-async fn delete(path: Path<String>) -> HandlerResult {
-    trace!("DELETE Triggered on {}", path);
-    error_400()
+async fn delete((path, state): (Path<String>, Data<RwLock<SharedState>>)) -> HandlerResult {
+    trace!("DELETE '{:?}'", path);
+    Access::current().require(Role::Write)?;
+    let name = path.to_string();
+    let mut context = state
+        .write()
+        .unwrap_or_else(|e| panic!("Can't acquire write lock of the database: {}", e));
+
+    let space = match context.db().space(&name) {
+        Err(_) => return error_404(),
+        Ok(space) => model::Space::from(space),
+    };
+
+    // A space can only be removed once no objects reference it anymore.
+    match context.space_in_use(&name) {
+        Err(e) => return error_500(e),
+        Ok(Some(core)) => {
+            trace!("Space '{}' still used by core '{}'", name, core);
+            return error_409();
+        }
+        Ok(None) => (),
+    }
+
+    match context.drop_space(&name) {
+        Err(e) => error_500(e),
+        Ok(()) => ok_200(&space),
+    }
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -54,6 +220,7 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 #[cfg(test)]
 mod routing {
     use super::super::tests_utils::*;
+    use serde_json::json;
 
     const INSTANCE_EXISTS: &str = SPACE;
     const INSTANCE_INVALID: &str = "/21-doesnotexists";
@@ -67,6 +234,73 @@ mod routing {
         json::expect_200(TestRequest::put(), &get_space(INSTANCE_INVALID), "".to_string()).await;
     }
 
+    fn space(name: &str, origin: serde_json::Value) -> serde_json::Value {
+        let axis = |unit_vector| {
+            json!({
+                "measurement_unit": "m",
+                "graduation": {"set": "R", "minimum": 0.0, "maximum": 1.0, "steps": 1000},
+                "unit_vector": unit_vector
+            })
+        };
+
+        json!({
+            "name": name,
+            "origin": origin,
+            "axes": [axis([1.0, 0.0, 0.0]), axis([0.0, 1.0, 0.0]), axis([0.0, 0.0, 1.0])]
+        })
+    }
+
+    #[actix_web::test]
+    async fn put_space() {
+        let new = INSTANCE_INVALID.trim_start_matches('/');
+        let existing = INSTANCE_EXISTS.trim_start_matches('/');
+
+        expect_200(
+            TestRequest::put().set_json(space(new, json!([0.0, 0.0, 0.0]))),
+            &get_space(INSTANCE_INVALID),
+        )
+        .await;
+        expect_200(
+            TestRequest::put().set_json(space(existing, json!([1.0, 0.0, 0.0]))),
+            &get_space(INSTANCE_EXISTS),
+        )
+        .await;
+        expect_422(
+            TestRequest::put().set_json(space(existing, json!([0.0, 0.0, 0.0]))),
+            &get_space(INSTANCE_INVALID),
+        )
+        .await;
+        expect_422(
+            TestRequest::put().set_json(space(new, json!([0.0, 0.0]))),
+            &get_space(INSTANCE_INVALID),
+        )
+        .await;
+
+        // The objects stored in the space would not fit anymore.
+        let mut narrow = space(existing, json!([0.0, 0.0, 0.0]));
+        narrow["axes"][0]["graduation"]["maximum"] = json!(1.0e-6);
+        expect_422(TestRequest::put().set_json(narrow), &get_space(INSTANCE_EXISTS)).await;
+    }
+
+    #[actix_web::test]
+    async fn patch_space() {
+        expect_200(
+            TestRequest::patch().set_json(json!({"origin": [1.0, 0.0, 0.0]})),
+            &get_space(INSTANCE_EXISTS),
+        )
+        .await;
+        expect_422(
+            TestRequest::patch().set_json(json!({"origin": [1.0, 0.0]})),
+            &get_space(INSTANCE_EXISTS),
+        )
+        .await;
+        expect_404(
+            TestRequest::patch().set_json(json!({"origin": [1.0, 0.0, 0.0]})),
+            &get_space(INSTANCE_INVALID),
+        )
+        .await;
+    }
+
     #[actix_web::test]
     async fn patch() {
         json::expect_200(TestRequest::patch(), &get_space(INSTANCE_EXISTS), "".to_string()).await;
@@ -82,7 +316,8 @@ mod routing {
 
     #[actix_web::test]
     async fn delete() {
-        expect_200(TestRequest::delete(), &get_space(INSTANCE_EXISTS)).await;
+        // The test core has objects in the test space.
+        expect_409(TestRequest::delete(), &get_space(INSTANCE_EXISTS)).await;
         expect_404(TestRequest::delete(), &get_space(INSTANCE_INVALID)).await;
     }
 
@@ -2,90 +2,146 @@ use std::collections::HashSet;
 use std::sync::RwLock;
 
 use super::error_400;
-use super::error_422;
+use super::error_500;
+use super::error_504;
 use super::model;
 use super::ok_200;
 use super::web;
 use super::web::Data;
 use super::web::Json;
+use super::Access;
+use super::ApiError;
 use super::CoreQueryParameters;
 use super::Filters;
 use super::HandlerResult;
 use super::SharedState;
+use crate::shared_state::Cancellation;
+use crate::shared_state::QueryError;
+
+// Retrieve the ids of the spaces used by the objects matching `filter`, in
+// the cores visible to the client.
+fn matching_spaces(
+    context: &SharedState,
+    access: &Access,
+    parameters: &Filters,
+    filter: &str,
+    cancellation: &Cancellation,
+) -> Result<Vec<String>, ApiError> {
+    let mut results = HashSet::new();
+
+    let core_parameters = CoreQueryParameters {
+        db: context.db(),
+        output_space: parameters.space.as_ref().map(String::as_str),
+        threshold_volume: parameters.volume(),
+        view_port: &parameters.view_port,
+        resolution: parameters.resolution(),
+    };
+    let tree = context.filter(filter)?;
+
+    // Only report the spaces used by the visible cores.
+    let spaces_by_cores = context.for_each_core(
+        |core| access.allows(core),
+        |core| {
+            match context.execute(&tree, core, &core_parameters, cancellation) {
+                Err(e) => Err(QueryError::execution(core, e)),
+                Ok(v) => {
+                    // We have a list of SpaceObjects, so extract
+                    // the space Ids
+                    Ok(v.into_iter()
+                        .map(|(space_id, _)| space_id.to_string())
+                        .collect::<Vec<_>>())
+                }
+            }
+        },
+    );
+
+    for (_, spaces) in spaces_by_cores {
+        match spaces {
+            Err(e) => return Err(e.into()),
+            Ok(spaces) => results.extend(spaces),
+        }
+    }
+
+    Ok(results.drain().collect())
+}
 
 async fn post((parameters, state): (Json<Filters>, Data<RwLock<SharedState>>)) -> HandlerResult {
     trace!("POST '{:?}'", parameters);
-    let context = state
-        .read()
-        .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
-    let db = context.db();
-
-    match parameters.space(db) {
-        Err(e) => e,
-        Ok(space) => {
-            match parameters.filters() {
-                None => {
-                    if parameters.ids_only() {
-                        ok_200(db.space_keys())
-                    } else {
-                        let spaces = db
-                            .space_keys()
-                            .iter()
-                            .filter_map(|id| match db.space(id) {
-                                Err(_) => None, // FIXME: Return error ?
-                                Ok(x) => Some(model::Space::from(x)),
-                            })
-                            .collect::<Vec<_>>();
-
-                        ok_200(&spaces)
-                    }
-                }
-                Some(filter) => {
-                    // Retrieve the list of space ids.
-                    let mut results = HashSet::new();
-
-                    let core_parameters = CoreQueryParameters {
-                        db: context.db(),
-                        output_space: space.as_ref().map(String::as_str),
-                        threshold_volume: parameters.volume(),
-                        view_port: &parameters.view_port,
-                        resolution: parameters.resolution(),
-                    };
-                    let tree = match context.filter(filter) {
-                        Err(e) => return error_422(e),
-                        Ok(bag) => bag,
-                    };
-
-                    for core in db.core_keys() {
-                        match context.execute(&tree, core, &core_parameters) {
-                            Err(e) => return error_422(e),
-                            Ok(v) => {
-                                // We have a list of SpaceObjects, so extract
-                                // the space Ids
-                                for (space_id, _) in v {
-                                    results.insert(space_id);
-                                }
-                            }
-                        }
-                    }
-
-                    // Format the list or the whole space objects.
-                    if parameters.ids_only() {
-                        ok_200(&results.drain().collect::<Vec<_>>())
-                    } else {
-                        ok_200(
-                            &results
-                                .drain()
-                                .map(|id| match db.space(id) {
-                                    Err(_) => None,
-                                    Ok(x) => Some(model::Space::from(x)),
-                                })
-                                .collect::<Vec<_>>(),
-                        )
-                    }
-                }
+    let filter = {
+        let context = state
+            .read()
+            .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+        let db = context.db();
+
+        if let Err(e) = parameters.space(db) {
+            return e;
+        }
+
+        match parameters.filters() {
+            Some(filter) => filter.to_string(),
+            None => {
+                return if parameters.ids_only() {
+                    ok_200(db.space_keys())
+                } else {
+                    let spaces = db
+                        .space_keys()
+                        .iter()
+                        .filter_map(|id| match db.space(id) {
+                            Err(_) => None, // FIXME: Return error ?
+                            Ok(x) => Some(model::Space::from(x)),
+                        })
+                        .collect::<Vec<_>>();
+
+                    ok_200(&spaces)
+                };
             }
         }
+    };
+
+    // Spaces are searched outside of the HTTP workers, and given up on when
+    // the client goes away.
+    let access = Access::current();
+    let cancellation = Cancellation::new(parameters.timeout());
+    let _cancel = cancellation.on_drop();
+    let ids_only = parameters.ids_only();
+    let results = {
+        let cancellation = cancellation.clone();
+        let parameters = parameters.into_inner();
+        let state = state.clone();
+        web::block(move || {
+            let context = state
+                .read()
+                .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+            matching_spaces(&context, &access, &parameters, &filter, &cancellation)
+        })
+        .await
+    };
+
+    let results = match results {
+        Err(e) => return error_500(e),
+        Ok(results) => results?,
+    };
+    if cancellation.is_interrupted() {
+        return error_504();
+    }
+
+    // Format the list or the whole space objects.
+    if ids_only {
+        ok_200(&results)
+    } else {
+        let context = state
+            .read()
+            .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+        let db = context.db();
+        ok_200(
+            &results
+                .iter()
+                .map(|id| match db.space(id) {
+                    Err(_) => None,
+                    Ok(x) => Some(model::Space::from(x)),
+                })
+                .collect::<Vec<_>>(),
+        )
     }
 }
 
@@ -1,16 +1,22 @@
+use std::collections::HashMap;
 use std::sync::RwLock;
 
 use super::error_400;
 use super::error_404;
 use super::from_properties_by_spaces;
 use super::ok_200;
+use super::spatial_objects::remove;
+use super::spatial_objects::update;
 use super::web;
 use super::web::Data;
+use super::web::Json;
 use super::web::Path;
+use super::Access;
 use super::CoreQueryParameters;
 use super::HandlerResult;
 use super::Properties;
 use super::SharedState;
+use super::SpatialObjectPartial;
 use mercator_db::{IterObjects, IterObjectsBySpaces};
 
 async fn put(path: Path<String>) -> HandlerResult {
@@ -36,7 +42,7 @@ async fn get((path, state): (Path<(String, String)>, Data<RwLock<SharedState>>))
         resolution: &Some(vec![0]),
     };
 
-    match db.core(&core) {
+    match Access::current().core(db, &core) {
         Ok(core) => match core.get_by_id(&parameters, &id) {
             Ok(positions_by_spaces) => {
                 let value = Properties::Feature(id);
@@ -63,14 +69,27 @@ async fn get((path, state): (Path<(String, String)>, Data<RwLock<SharedState>>))
     }
 }
 
-async fn patch(path: Path<String>) -> HandlerResult {
-    trace!("PATCH Triggered on {}", path);
-    error_400()
+async fn patch(
+    (path, patch, state): (
+        Path<(String, String)>,
+        Json<SpatialObjectPartial>,
+        Data<RwLock<SharedState>>,
+    ),
+) -> HandlerResult {
+    trace!("PATCH '{:?}', {:?}", patch, path);
+    let (core, id) = path.into_inner();
+
+    let mut patches = HashMap::new();
+    patches.insert(id, patch.into_inner());
+
+    update(state, core, patches).await
 }
 
-async fn delete(path: Path<String>) -> HandlerResult {
-    trace!("DELETE Triggered on {}", path);
-    error_400()
+async fn delete((path, state): (Path<(String, String)>, Data<RwLock<SharedState>>)) -> HandlerResult {
+    trace!("DELETE '{:?}'", path);
+    let (core, id) = path.into_inner();
+
+    remove(state, core, vec![id]).await
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -86,6 +105,7 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 #[cfg(test)]
 mod routing {
     use super::super::tests_utils::*;
+    use serde_json::json;
 
     const INSTANCE_EXISTS: &str = SPATIAL_OBJECT;
     const INSTANCE_INVALID: &str = "/21-doesnotexists";
@@ -106,6 +126,33 @@ mod routing {
         expect_400(TestRequest::patch(), &get_objects(INSTANCE_INVALID)).await;
     }
 
+    #[actix_web::test]
+    async fn patch_object() {
+        let shapes = json!([{"space": "std", "shapes": [{"points": [[0.5, 0.5, 0.5]]}]}]);
+        let invalid = json!([{"space": "INVALID", "shapes": [{"points": [[0.5, 0.5, 0.5]]}]}]);
+
+        expect_200(
+            TestRequest::patch().set_json(json!({"type": "Region"})),
+            &get_objects(INSTANCE_EXISTS),
+        )
+        .await;
+        expect_200(
+            TestRequest::patch().set_json(json!({"add": shapes})),
+            &get_objects(INSTANCE_EXISTS),
+        )
+        .await;
+        expect_422(
+            TestRequest::patch().set_json(json!({"add": invalid})),
+            &get_objects(INSTANCE_EXISTS),
+        )
+        .await;
+        expect_404(
+            TestRequest::patch().set_json(json!({"type": "Region"})),
+            &get_objects(INSTANCE_INVALID),
+        )
+        .await;
+    }
+
     #[actix_web::test]
     async fn get() {
         expect_200(TestRequest::get(), &get_objects(INSTANCE_EXISTS)).await;
@@ -118,6 +165,19 @@ mod routing {
         expect_404(TestRequest::delete(), &get_objects(INSTANCE_INVALID)).await;
     }
 
+    #[actix_web::test]
+    async fn delete_read_back() {
+        let object = get_objects(INSTANCE_EXISTS);
+
+        expect_sequence(vec![
+            (TestRequest::get(), object.clone(), 200),
+            (TestRequest::delete(), object.clone(), 200),
+            (TestRequest::get(), object.clone(), 404),
+            (TestRequest::delete(), object.clone(), 404),
+        ])
+        .await;
+    }
+
     #[actix_web::test]
     async fn post() {
         expect_405(TestRequest::post(), &get_objects(INSTANCE_EXISTS)).await;
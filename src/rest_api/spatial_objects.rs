@@ -1,119 +1,708 @@
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::RwLock;
 
-use super::error_400;
+use mercator_db::space::Position;
+use mercator_db::{IterObjects, IterObjectsBySpaces};
+use serde::Serialize;
+
+use super::aggregate;
+use super::aggregate::Aggregate;
+use super::aggregate::CoreAggregate;
+use super::encode_cursor;
 use super::error_404;
-use super::error_422;
+use super::error_409;
+use super::error_422_field;
+use super::error_500;
+use super::error_504;
+use super::export;
 use super::from_properties_by_spaces;
 use super::from_spaces_by_properties;
+use super::model;
+use super::ndjson;
 use super::ok_200;
 use super::web;
 use super::web::Data;
 use super::web::Json;
 use super::web::Path;
+use super::Access;
+use super::Aggregation;
+use super::ApiError;
 use super::CoreQueryParameters;
 use super::Filters;
 use super::HandlerResult;
+use super::HttpRequest;
+use super::Page;
+use super::Properties;
+use super::Role;
 use super::SharedState;
+use super::SpatialObjectPartial;
+use crate::shared_state::Cancellation;
+use crate::shared_state::IndexJob;
+use crate::shared_state::QueryError;
+use crate::shared_state::Truncation;
 
-async fn post(
-    (core_id, parameters, state): (Path<String>, Json<Filters>, Data<RwLock<SharedState>>),
+/// Serialize `items`, wrapped in a `Page` when pagination was requested or
+/// when they were truncated.
+fn respond<T: Serialize>(
+    parameters: &Filters,
+    items: Vec<T>,
+    next: Option<String>,
+    truncation: &Truncation,
 ) -> HandlerResult {
-    trace!("POST '{:?}', {:?}", parameters, core_id);
-    let core_id = core_id.to_string();
-    let context = state
-        .read()
-        .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+    if parameters.paginated() || truncation.is_truncated() {
+        ok_200(&Page::new(items, next, truncation.is_truncated()))
+    } else {
+        ok_200(&items)
+    }
+}
+
+// Build the objects of a page from `selected`, the objects of the page with
+// their positions by reference space, as long as they fit within the size
+// limits of `truncation`. Returns them with the cursor to the following page,
+// which is `next` unless objects were left out.
+fn fill_page<'a, I>(
+    selected: I,
+    next: Option<String>,
+    truncation: &Truncation,
+) -> (Vec<model::v2::SpatialObject>, Option<String>)
+where
+    I: Iterator<Item = (&'a Properties, Vec<(&'a String, Vec<Position>)>)>,
+{
+    let (selected, cut) = truncation.fit(selected, |(_, by_spaces)| {
+        by_spaces.iter().map(|(_, positions)| positions.len()).sum()
+    });
+    let next = match selected.last() {
+        Some((properties, _)) if cut => Some(encode_cursor(properties.id())),
+        _ => next,
+    };
+
+    let objects_by_spaces: IterObjectsBySpaces = selected
+        .into_iter()
+        .flat_map(|(properties, by_spaces)| {
+            by_spaces.into_iter().map(move |(space, positions)| {
+                let objects: IterObjects =
+                    Box::new(positions.into_iter().map(move |position| (position, properties)));
+                (space, objects)
+            })
+        })
+        .collect();
+
+    let mut objects = from_properties_by_spaces(objects_by_spaces).collect::<Vec<_>>();
+    objects.sort_by(|a, b| a.properties.id.cmp(&b.properties.id));
+
+    (objects, next)
+}
+
+// Stream the objects, or their ids, one per line as they are retrieved.
+fn stream_objects(
+    context: &SharedState,
+    access: &Access,
+    core_id: &str,
+    parameters: &Filters,
+    cancellation: &Cancellation,
+    lines: &mut ndjson::Lines,
+) -> Result<(), ApiError> {
     let db = context.db();
+    let core = match access.core(db, core_id) {
+        Err(_) => return error_404().map(drop),
+        Ok(core) => core,
+    };
+    let space = match parameters.space(db) {
+        Err(e) => return e.map(drop),
+        Ok(space) => space,
+    };
+    let core_parameters = CoreQueryParameters {
+        db,
+        output_space: space.as_ref().map(String::as_str),
+        threshold_volume: parameters.volume(),
+        view_port: &parameters.view_port,
+        resolution: parameters.resolution(),
+    };
 
-    match db.core(&core_id) {
-        Err(_) => error_404(),
-        Ok(core) => match parameters.space(db) {
-            Err(e) => e,
-            Ok(space) => match parameters.filters() {
-                None => {
-                    if parameters.ids_only() {
-                        // keys() contains unique values only.
-                        let ids = core
-                            .keys()
-                            .iter()
-                            .map(|properties| properties.id())
-                            .collect::<Vec<_>>();
-
-                        ok_200(&ids)
-                    } else {
-                        let core_parameters = CoreQueryParameters {
-                            db,
-                            output_space: space.as_ref().map(String::as_str),
-                            threshold_volume: parameters.volume(),
-                            view_port: &parameters.view_port,
-                            resolution: parameters.resolution(),
-                        };
-
-                        let objects_by_spaces =
-                            Box::new(core.keys().iter().filter_map(|property| {
-                                match core.get_by_id(&core_parameters, property.id()) {
-                                    Err(_) => None, // FIXME: Return error ?
-                                    Ok(positions_by_spaces) => {
-                                        Some((property, positions_by_spaces))
-                                    }
-                                }
-                            }));
-                        ok_200(&from_spaces_by_properties(objects_by_spaces).collect::<Vec<_>>())
+    let sent = match parameters.filters() {
+        None => {
+            if parameters.ids_only() {
+                // keys() contains unique values only.
+                core.keys().iter().all(|properties| lines.send(properties.id()))
+            } else {
+                let keys = core.keys().iter();
+                let keys = keys.take_while(|_| cancellation.proceed());
+                let objects_by_spaces = Box::new(keys.filter_map(|property| {
+                    match core.get_by_id(&core_parameters, property.id()) {
+                        Err(_) => None, // FIXME: Return error ?
+                        Ok(positions_by_spaces) => Some((property, positions_by_spaces)),
+                    }
+                }));
+
+                from_spaces_by_properties(objects_by_spaces).all(|object| lines.send(&object))
+            }
+        }
+        Some(filter) => {
+            let tree = context.filter(filter)?;
+            let objects = match context.execute(&tree, core_id, &core_parameters, cancellation) {
+                Err(e) => return Err(QueryError::execution(core_id, e).into()),
+                Ok(objects) => objects,
+            };
+
+            if parameters.ids_only() {
+                let mut uniques = HashSet::new();
+                for (_, v) in objects {
+                    for (_, properties) in v {
+                        uniques.insert(properties.id());
                     }
                 }
-                Some(filter) => {
-                    let core_parameters = CoreQueryParameters {
-                        db,
-                        output_space: space.as_ref().map(String::as_str),
-                        threshold_volume: parameters.volume(),
-                        view_port: &parameters.view_port,
-                        resolution: parameters.resolution(),
-                    };
-
-                    let tree = match context.filter(filter) {
-                        Err(e) => return error_422(e),
-                        Ok(bag) => bag,
-                    };
-
-                    let r = match context.execute(&tree, &core_id, &core_parameters) {
-                        Err(e) => error_422(e),
-                        Ok(objects) => {
-                            if parameters.ids_only() {
-                                let mut uniques = HashSet::new();
-                                for (_, v) in objects {
-                                    for (_, properties) in v {
-                                        uniques.insert(properties.id());
-                                    }
-                                }
 
-                                ok_200(&uniques.drain().collect::<Vec<_>>())
-                            } else {
-                                ok_200(&from_properties_by_spaces(objects).collect::<Vec<_>>())
+                // Ids are only sent once complete, as they are deduplicated.
+                if cancellation.is_interrupted() {
+                    return error_504().map(drop);
+                }
+
+                uniques.into_iter().all(|id| lines.send(id))
+            } else {
+                from_properties_by_spaces(objects).all(|object| lines.send(&object))
+            }
+        }
+    };
+
+    if !sent {
+        trace!("Client disconnected, stream aborted");
+        return Ok(());
+    }
+
+    // The objects sent so far are only part of the results.
+    if cancellation.is_interrupted() {
+        return error_504().map(drop);
+    }
+
+    Ok(())
+}
+
+// Compute statistics on the objects of `core_id` matching `parameters`.
+fn aggregate_objects(
+    context: &SharedState,
+    core_id: &str,
+    parameters: &Filters,
+    aggregation: Aggregation,
+    cancellation: &Cancellation,
+) -> Result<Aggregate, ApiError> {
+    let db = context.db();
+    let core = match db.core(core_id) {
+        Err(_) => return error_404().map(|_| unreachable!()),
+        Ok(core) => core,
+    };
+    let core_parameters = CoreQueryParameters {
+        db,
+        output_space: parameters.space.as_ref().map(String::as_str),
+        threshold_volume: parameters.volume(),
+        view_port: &parameters.view_port,
+        resolution: parameters.resolution(),
+    };
+
+    let boxes = aggregate::histogram_boxes(context, parameters);
+    let aggregate = match parameters.filters() {
+        None => CoreAggregate::new(
+            core_id,
+            cancellation.guard(aggregate::everything(core, &core_parameters)),
+            aggregation,
+            &boxes,
+        ),
+        Some(filter) => {
+            let tree = context.filter(filter)?;
+            match context.execute(&tree, core_id, &core_parameters, cancellation) {
+                Err(e) => return Err(QueryError::execution(core_id, e).into()),
+                Ok(objects) => CoreAggregate::new(core_id, objects, aggregation, &boxes),
+            }
+        }
+    };
+
+    Ok(Aggregate::new(vec![aggregate]))
+}
+
+// Objects of a core, or their ids, before they are serialized.
+enum Listing {
+    Aggregate(Aggregate),
+    Ids(Vec<String>, Option<String>),
+    Objects(Vec<model::v2::SpatialObject>, Option<String>),
+    Export(export::Format, Vec<model::v2::SpatialObject>),
+}
+
+// List the objects of `core_id` matching `parameters`, once its output space
+// has been validated. The results are cut to the size limits.
+fn list_objects(
+    context: &SharedState,
+    core_id: &str,
+    parameters: &Filters,
+    cancellation: &Cancellation,
+    truncation: &Truncation,
+) -> Result<Listing, ApiError> {
+    let db = context.db();
+    let core = match db.core(core_id) {
+        Err(_) => return error_404().map(|_| unreachable!()),
+        Ok(core) => core,
+    };
+
+    match parameters.filters() {
+        None => {
+            // keys() contains unique values only.
+            let keys = core.keys().iter().collect::<Vec<_>>();
+            let (keys, next) = if parameters.paginated() {
+                match parameters.page(keys, |properties| properties.id(), truncation) {
+                    Err(e) => return e.map(|_| unreachable!()),
+                    Ok(page) => page,
+                }
+            } else {
+                (truncation.limit(keys.into_iter()).collect(), None)
+            };
+
+            if parameters.ids_only() {
+                let ids = keys
+                    .into_iter()
+                    .map(|properties| properties.id().to_string())
+                    .collect::<Vec<_>>();
+
+                Ok(Listing::Ids(ids, next))
+            } else {
+                let core_parameters = CoreQueryParameters {
+                    db,
+                    output_space: parameters.space.as_ref().map(String::as_str),
+                    threshold_volume: parameters.volume(),
+                    view_port: &parameters.view_port,
+                    resolution: parameters.resolution(),
+                };
+
+                // Only the objects of the page are retrieved, their
+                // positions counting towards the size limits.
+                let keys = keys.into_iter().take_while(|_| cancellation.proceed());
+                if parameters.paginated() {
+                    let selected = keys.filter_map(|properties| {
+                        match core.get_by_id(&core_parameters, properties.id()) {
+                            Err(_) => None, // FIXME: Return error ?
+                            Ok(positions_by_spaces) => Some((
+                                properties,
+                                positions_by_spaces
+                                    .into_iter()
+                                    .map(|(space, positions)| {
+                                        (space, positions.collect::<Vec<_>>())
+                                    })
+                                    .collect::<Vec<_>>(),
+                            )),
+                        }
+                    });
+
+                    let (objects, next) = fill_page(selected, next, truncation);
+                    Ok(Listing::Objects(objects, next))
+                } else {
+                    let mut objects_by_spaces: IterObjectsBySpaces = vec![];
+                    for properties in keys {
+                        match core.get_by_id(&core_parameters, properties.id()) {
+                            Err(_) => (), // FIXME: Return error ?
+                            Ok(positions_by_spaces) => {
+                                for (space, positions) in positions_by_spaces {
+                                    let objects: IterObjects = Box::new(
+                                        positions.map(move |position| (position, properties)),
+                                    );
+                                    objects_by_spaces.push((space, objects));
+                                }
                             }
                         }
-                    };
+                    }
+                    let mut objects = from_properties_by_spaces(truncation.guard(objects_by_spaces))
+                        .collect::<Vec<_>>();
+                    objects.sort_by(|a, b| a.properties.id.cmp(&b.properties.id));
+
+                    match parameters.export() {
+                        Some(format) => Ok(Listing::Export(format, objects)),
+                        None => Ok(Listing::Objects(objects, next)),
+                    }
+                }
+            }
+        }
+        Some(filter) => {
+            let core_parameters = CoreQueryParameters {
+                db,
+                output_space: parameters.space.as_ref().map(String::as_str),
+                threshold_volume: parameters.volume(),
+                view_port: &parameters.view_port,
+                resolution: parameters.resolution(),
+            };
+
+            let tree = match context.filter(filter) {
+                Err(e) => return Err(e.into()),
+                Ok(bag) => bag,
+            };
+
+            let objects = match context.execute(&tree, core_id, &core_parameters, cancellation) {
+                Err(e) => return Err(QueryError::execution(core_id, e).into()),
+                // Pages are cut to the size limits once selected, so
+                // the following ones remain reachable.
+                Ok(objects) if parameters.paginated() => objects,
+                Ok(objects) => truncation.guard(objects),
+            };
+
+            if parameters.ids_only() {
+                let mut uniques = HashSet::new();
+                for (_, v) in objects {
+                    for (_, properties) in v {
+                        uniques.insert(properties.id());
+                    }
+                }
+
+                if parameters.paginated() {
+                    let ids = uniques.drain().collect::<Vec<_>>();
+                    match parameters.page(ids, |id| id.as_str(), truncation) {
+                        Err(e) => e.map(|_| unreachable!()),
+                        Ok((ids, next)) => {
+                            Ok(Listing::Ids(ids.into_iter().cloned().collect(), next))
+                        }
+                    }
+                } else {
+                    let ids = truncation.limit(uniques.drain()).cloned().collect::<Vec<_>>();
+                    Ok(Listing::Ids(ids, None))
+                }
+            } else if parameters.paginated() {
+                // Select the ids of the page before building the
+                // objects, so only those are serialized.
+                let objects = objects
+                    .into_iter()
+                    .map(|(space, v)| (space, v.collect::<Vec<_>>()))
+                    .collect::<Vec<_>>();
+                let ids = objects
+                    .iter()
+                    .flat_map(|(_, v)| v.iter().map(|&(_, properties)| properties.id()))
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect::<Vec<_>>();
+
+                let (ids, next) = match parameters.page(ids, |id| id.as_str(), truncation) {
+                    Err(e) => return e.map(|_| unreachable!()),
+                    Ok(page) => page,
+                };
+                let page = ids.iter().copied().collect::<HashSet<_>>();
+
+                // Positions of the objects of the page, by space.
+                let mut by_ids = HashMap::new();
+                for (space, v) in objects {
+                    for (position, properties) in v {
+                        if !page.contains(properties.id()) {
+                            continue;
+                        }
+
+                        let (_, by_spaces) = by_ids
+                            .entry(properties.id())
+                            .or_insert_with(|| (properties, vec![]));
+                        match by_spaces.iter_mut().find(|(s, _)| *s == space) {
+                            Some((_, positions)) => positions.push(position),
+                            None => by_spaces.push((space, vec![position])),
+                        }
+                    }
+                }
+
+                let selected = ids.into_iter().filter_map(|id| by_ids.remove(id));
+                let (objects, next) = fill_page(selected, next, truncation);
+
+                Ok(Listing::Objects(objects, next))
+            } else {
+                let objects = truncation
+                    .limit(from_properties_by_spaces(objects))
+                    .collect::<Vec<_>>();
+
+                match parameters.export() {
+                    Some(format) => Ok(Listing::Export(format, objects)),
+                    None => Ok(Listing::Objects(objects, None)),
+                }
+            }
+        }
+    }
+}
+
+async fn post(
+    (request, core_id, parameters, state): (
+        HttpRequest,
+        Path<String>,
+        Json<Filters>,
+        Data<RwLock<SharedState>>,
+    ),
+) -> HandlerResult {
+    trace!("POST '{:?}', {:?}", parameters, core_id);
+    let core_id = core_id.to_string();
+
+    if parameters.export().is_some() {
+        if parameters.ids_only() {
+            return error_422_field("export", "Exports can't be made of ids only");
+        }
+        if parameters.paginated() || ndjson::requested(&request) {
+            return error_422_field("export", "Exports can't be paginated nor streamed");
+        }
+    }
+
+    let aggregation = parameters.aggregate();
+    if aggregation.is_some()
+        && (parameters.paginated() || parameters.export().is_some() || ndjson::requested(&request))
+    {
+        return error_422_field(
+            "aggregate",
+            "Aggregates can't be paginated, exported nor streamed",
+        );
+    }
+
+    if ndjson::requested(&request) {
+        if parameters.paginated() {
+            return error_422_field(
+                "stream",
+                "Streamed responses can't be paginated",
+            );
+        }
+
+        // The stream is produced on another thread, outside of the request.
+        let access = Access::current();
+        let cancellation = Cancellation::new(parameters.timeout());
+        let parameters = parameters.into_inner();
+        return ndjson::stream(&request, state, move |context, lines| {
+            stream_objects(context, &access, &core_id, &parameters, &cancellation, lines)
+        })
+        .await;
+    }
+
+    {
+        let context = state
+            .read()
+            .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+        let db = context.db();
+
+        if Access::current().core(db, &core_id).is_err() {
+            return error_404();
+        }
+        if let Err(e) = parameters.space(db) {
+            return e;
+        }
+    }
+
+    // Objects are retrieved outside of the HTTP workers, and given up on when
+    // the client goes away.
+    let cancellation = Cancellation::new(parameters.timeout());
+    let _cancel = cancellation.on_drop();
+    let truncation = Truncation::new();
+    let listing = {
+        let cancellation = cancellation.clone();
+        let truncation = truncation.clone();
+        let parameters = parameters.clone();
+        web::block(move || {
+            let context = state
+                .read()
+                .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+            match aggregation {
+                Some(aggregation) => {
+                    aggregate_objects(&context, &core_id, &parameters, aggregation, &cancellation)
+                        .map(Listing::Aggregate)
+                }
+                None => list_objects(&context, &core_id, &parameters, &cancellation, &truncation),
+            }
+        })
+        .await
+    };
+
+    let listing = match listing {
+        Err(e) => return error_500(e),
+        Ok(listing) => listing?,
+    };
 
-                    r
+    // Do not pass incomplete results for complete ones.
+    if cancellation.is_interrupted() {
+        return error_504();
+    }
+
+    match listing {
+        Listing::Aggregate(aggregate) => ok_200(&aggregate),
+        Listing::Ids(ids, next) => respond(&parameters, ids, next, &truncation),
+        Listing::Objects(objects, next) => respond(&parameters, objects, next, &truncation),
+        Listing::Export(format, objects) => {
+            let space = parameters.space.as_deref();
+            export::render(format, space, &objects, truncation.is_truncated())
+        }
+    }
+}
+
+// Number of times a modification is attempted when the database is modified
+// concurrently.
+pub const MODIFY_ATTEMPTS: usize = 3;
+
+/// Apply `change` to a snapshot of the core `core_id`, then rebuild its index
+/// without holding the lock on the database, and install it.
+///
+/// `change` checks the request against the snapshot, and returns the number
+/// of objects modified. The index is left as is when there are none.
+pub async fn modify<F>(
+    state: Data<RwLock<SharedState>>,
+    core_id: String,
+    change: F,
+) -> HandlerResult
+where
+    F: Fn(&SharedState, &mut IndexJob) -> Result<usize, ApiError> + Send + 'static,
+{
+    let modified = web::block(move || {
+        for _ in 0..MODIFY_ATTEMPTS {
+            let (job, count) = {
+                let context = state
+                    .read()
+                    .unwrap_or_else(|e| panic!("Can't acquire read lock of the database: {}", e));
+
+                if context.db().core(&core_id).is_err() {
+                    return error_404().map(|_| 0);
                 }
-            },
-        },
+
+                let mut job = match context.index_job(&core_id, None) {
+                    Err(e) => return error_500(e).map(|_| 0),
+                    Ok(job) => job,
+                };
+
+                match change(&context, &mut job)? {
+                    0 => return Ok(0),
+                    count => (job, count),
+                }
+            };
+
+            // The current index keeps serving queries until the new one is
+            // ready.
+            let core = job.build();
+
+            let installed = state
+                .write()
+                .unwrap_or_else(|e| panic!("Can't acquire write lock of the database: {}", e))
+                .install(job, core);
+
+            match installed {
+                Err(e) => return error_500(e).map(|_| 0),
+                Ok(true) => return Ok(count),
+                Ok(false) => trace!("Core '{}' modified concurrently, retrying", core_id),
+            }
+        }
+
+        error_409().map(|_| 0)
+    })
+    .await;
+
+    match modified {
+        Err(e) => error_500(e),
+        Ok(count) => ok_200(&count?),
     }
 }
 
-async fn put() -> HandlerResult {
-    trace!("PUT Triggered!");
-    error_400()
+// Check every id of `ids` is part of `job`, either all the objects are
+// modified, or none.
+fn check_ids(job: &IndexJob, ids: &[String]) -> Result<(), ApiError> {
+    let unknown = job.unknown_ids(ids);
+    if !unknown.is_empty() {
+        trace!("Unknown ids: {:?}", unknown);
+        return error_404().map(drop);
+    }
+
+    Ok(())
 }
 
-async fn patch() -> HandlerResult {
-    trace!("PATCH Triggered!");
-    error_400()
+async fn put(
+    (core_id, objects, state): (
+        Path<String>,
+        Json<Vec<model::v2::SpatialObject>>,
+        Data<RwLock<SharedState>>,
+    ),
+) -> HandlerResult {
+    trace!("PUT {} objects, {:?}", objects.len(), core_id);
+    let core_id = core_id.to_string();
+    let access = Access::current();
+    access.require(Role::Write)?;
+    if !access.allows(&core_id) {
+        return error_404();
+    }
+
+    let objects = objects.into_inner();
+    modify(state, core_id, move |context, job| {
+        if let Err(e) = context.validate(objects.iter().flat_map(|object| &object.volumes)) {
+            return error_422_field("volumes", e).map(|_| 0);
+        }
+
+        Ok(job.insert(objects.clone()))
+    })
+    .await
 }
 
-async fn delete() -> HandlerResult {
-    trace!("DELETE Triggered!");
-    error_400()
+/// Apply `patches`, indexed by object id, to the objects of `core_id`.
+pub async fn update(
+    state: Data<RwLock<SharedState>>,
+    core_id: String,
+    patches: HashMap<String, SpatialObjectPartial>,
+) -> HandlerResult {
+    let access = Access::current();
+    access.require(Role::Write)?;
+    if !access.allows(&core_id) {
+        return error_404();
+    }
+
+    let ids = patches.keys().cloned().collect::<Vec<_>>();
+    modify(state, core_id, move |context, job| {
+        check_ids(job, &ids)?;
+
+        if let Err(e) = context.validate(patches.values().flat_map(|patch| patch.volumes())) {
+            return error_422_field("volumes", e).map(|_| 0);
+        }
+
+        let graduations = context.graduations();
+        Ok(job.update(|object| match patches.get(&object.properties.id) {
+            None => false,
+            Some(patch) => patch.apply(object, &graduations),
+        }))
+    })
+    .await
+}
+
+async fn patch(
+    (core_id, patches, state): (
+        Path<String>,
+        Json<Vec<SpatialObjectPartial>>,
+        Data<RwLock<SharedState>>,
+    ),
+) -> HandlerResult {
+    trace!("PATCH '{:?}', {:?}", patches, core_id);
+
+    let mut patches_by_id = HashMap::new();
+    for patch in patches.into_inner() {
+        match patch.id().clone() {
+            None => return error_422_field("id", format!("Missing object id in '{:?}'", patch)),
+            Some(id) => {
+                patches_by_id.insert(id, patch);
+            }
+        }
+    }
+
+    update(state, core_id.into_inner(), patches_by_id).await
+}
+
+/// Remove the objects `ids` from `core_id`.
+pub async fn remove(
+    state: Data<RwLock<SharedState>>,
+    core_id: String,
+    ids: Vec<String>,
+) -> HandlerResult {
+    let access = Access::current();
+    access.require(Role::Write)?;
+    if !access.allows(&core_id) {
+        return error_404();
+    }
+
+    modify(state, core_id, move |_, job| {
+        check_ids(job, &ids)?;
+
+        Ok(job.remove(&ids))
+    })
+    .await
+}
+
+async fn delete(
+    (core_id, ids, state): (Path<String>, Json<Vec<String>>, Data<RwLock<SharedState>>),
+) -> HandlerResult {
+    trace!("DELETE '{:?}', {:?}", ids, core_id);
+
+    remove(state, core_id.into_inner(), ids.into_inner()).await
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -129,6 +718,7 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 #[cfg(test)]
 mod routing {
     use super::super::tests_utils::*;
+    use serde_json::json;
 
     // FIXME: Add Body to request to see difference between (in)valid bodied requests
 
@@ -142,6 +732,96 @@ mod routing {
         expect_400(TestRequest::post(), &get_objects("")).await;
     }
 
+    #[actix_web::test]
+    async fn post_page() {
+        let page = |parameters| TestRequest::post().set_json(parameters);
+
+        expect_200(page(json!({"limit": 1})), &get_objects("")).await;
+        expect_200(page(json!({"ids_only": false, "limit": 1})), &get_objects("")).await;
+        expect_200(page(json!({"offset": 1000000})), &get_objects("")).await;
+        expect_200(page(json!({"cursor": "6f6964", "limit": 1})), &get_objects("")).await;
+        expect_422(page(json!({"cursor": "not a cursor"})), &get_objects("")).await;
+        expect_400(page(json!({"limit": -1})), &get_objects("")).await;
+    }
+
+    #[actix_web::test]
+    async fn post_stream() {
+        let ndjson = || TestRequest::post().insert_header(("Accept", "application/x-ndjson"));
+        let stream = format!("{}?stream=true", get_objects(""));
+        let invalid_core = format!("{}{}", get_core(INVALID_CORE), "/spatial_objects");
+
+        expect_200(ndjson().set_json(json!({})), &get_objects("")).await;
+        expect_200(ndjson().set_json(json!({"ids_only": false})), &get_objects("")).await;
+        expect_200(TestRequest::post().set_json(json!({})), &stream).await;
+        expect_404(ndjson().set_json(json!({})), &invalid_core).await;
+        expect_422(ndjson().set_json(json!({"limit": 1})), &get_objects("")).await;
+        expect_422(ndjson().set_json(json!({"space": "INVALID"})), &get_objects("")).await;
+    }
+
+    #[actix_web::test]
+    async fn post_timeout() {
+        let ep = &get_objects("");
+        let ndjson = || TestRequest::post().insert_header(("Accept", "application/x-ndjson"));
+        let filter = json!({
+            "filters": "inside(hyperrectangle{[0,0,0],[0,1,1]})",
+            "timeout_ms": 0
+        });
+
+        expect_504(TestRequest::post().set_json(&filter), ep).await;
+        expect_504(ndjson().set_json(&filter), ep).await;
+        expect_504(
+            TestRequest::post().set_json(json!({"aggregate": "count", "timeout_ms": 0})),
+            ep,
+        )
+        .await;
+        expect_200(
+            TestRequest::post().set_json(json!({
+                "filters": "inside(hyperrectangle{[0,0,0],[0,1,1]})",
+                "timeout_ms": 60000
+            })),
+            ep,
+        )
+        .await;
+        expect_422(
+            TestRequest::post().set_json(json!({"filters": "toto", "timeout_ms": 0})),
+            ep,
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn post_aggregate() {
+        let aggregate = |parameters| TestRequest::post().set_json(parameters);
+        let filters = "inside(hyperrectangle{[0,0,0],[0,1,1]})";
+        let invalid_core = format!("{}{}", get_core(INVALID_CORE), "/spatial_objects");
+
+        for aggregation in &["count", "bbox", "histogram"] {
+            expect_200(aggregate(json!({"aggregate": aggregation})), &get_objects("")).await;
+            expect_200(
+                aggregate(json!({"aggregate": aggregation, "filters": filters})),
+                &get_objects(""),
+            )
+            .await;
+        }
+        expect_404(aggregate(json!({"aggregate": "count"})), &invalid_core).await;
+        expect_422(aggregate(json!({"aggregate": "count", "limit": 1})), &get_objects("")).await;
+        expect_422(aggregate(json!({"aggregate": "count", "filters": "toto"})), &get_objects(""))
+            .await;
+        expect_400(aggregate(json!({"aggregate": "sum"})), &get_objects("")).await;
+    }
+
+    #[actix_web::test]
+    async fn post_export() {
+        let export = |parameters| TestRequest::post().set_json(parameters);
+
+        for format in &["ply", "obj", "gltf", "csv"] {
+            expect_200(export(json!({"export": format})), &get_objects("")).await;
+        }
+        expect_422(export(json!({"export": "ply", "ids_only": true})), &get_objects("")).await;
+        expect_422(export(json!({"export": "ply", "limit": 1})), &get_objects("")).await;
+        expect_400(export(json!({"export": "stl"})), &get_objects("")).await;
+    }
+
     #[actix_web::test]
     async fn put() {
         json::expect_200(TestRequest::put(), &get_objects(""), "".to_string()).await;
@@ -151,6 +831,52 @@ mod routing {
         expect_400(TestRequest::put(), &get_objects("")).await;
     }
 
+    #[actix_web::test]
+    async fn put_objects() {
+        let shape = |space: &str, point| {
+            json!([{
+                "properties": {"type": "Feature", "id": "oid-put"},
+                "volumes": [{"space": space, "shapes": [{"points": [point]}]}]
+            }])
+        };
+        let object = |space: &str| shape(space, json!([0.5, 0.5, 0.5]));
+        let invalid_core = format!("{}{}", get_core(INVALID_CORE), "/spatial_objects");
+
+        expect_200(TestRequest::put().set_json(object("std")), &get_objects("")).await;
+        expect_422(TestRequest::put().set_json(object("INVALID")), &get_objects("")).await;
+        expect_422(
+            TestRequest::put().set_json(shape("std", json!([0.5, 0.5]))),
+            &get_objects(""),
+        )
+        .await;
+        expect_422(
+            TestRequest::put().set_json(shape("std", json!([0.5, 0.5, 1.0e12]))),
+            &get_objects(""),
+        )
+        .await;
+        expect_404(TestRequest::put().set_json(object("std")), &invalid_core).await;
+        expect_400(TestRequest::put().set_json(json!({})), &get_objects("")).await;
+    }
+
+    #[actix_web::test]
+    async fn put_read_back() {
+        let objects = json!([{
+            "properties": {"type": "Feature", "id": "oid-put"},
+            "volumes": [{"space": "std", "shapes": [{"points": [[0.5, 0.5, 0.5]]}]}]
+        }]);
+        let object = get_objects("/oid-put");
+
+        let bodies = expect_sequence(vec![
+            (TestRequest::get(), object.clone(), 404),
+            (TestRequest::put().set_json(objects), get_objects(""), 200),
+            (TestRequest::get(), object.clone(), 200),
+        ])
+        .await;
+
+        let objects = serde_json::from_slice::<serde_json::Value>(&bodies[2]).unwrap();
+        assert_eq!(objects[0]["properties"]["id"], "oid-put");
+    }
+
     #[actix_web::test]
     async fn patch() {
         json::expect_200(TestRequest::patch(), &get_objects(""), "".to_string()).await;
@@ -160,6 +886,89 @@ mod routing {
         expect_400(TestRequest::patch(), &get_objects("")).await;
     }
 
+    #[actix_web::test]
+    async fn patch_objects() {
+        let existing = SPATIAL_OBJECT.trim_start_matches('/');
+        let shapes = json!([{"space": "std", "shapes": [{"points": [[0.5, 0.5, 0.5]]}]}]);
+        let invalid = json!([{"space": "INVALID", "shapes": [{"points": [[0.5, 0.5, 0.5]]}]}]);
+        let outside = json!([{"space": "std", "shapes": [{"points": [[0.5, 0.5, 1.0e12]]}]}]);
+
+        expect_200(
+            TestRequest::patch().set_json(json!([{"id": existing, "type": "Region"}])),
+            &get_objects(""),
+        )
+        .await;
+        expect_200(
+            TestRequest::patch().set_json(json!([{"id": existing, "add": shapes}])),
+            &get_objects(""),
+        )
+        .await;
+        expect_200(
+            TestRequest::patch().set_json(json!([{"id": existing, "remove": shapes}])),
+            &get_objects(""),
+        )
+        .await;
+        expect_422(
+            TestRequest::patch().set_json(json!([{"id": existing, "add": invalid}])),
+            &get_objects(""),
+        )
+        .await;
+        expect_422(
+            TestRequest::patch().set_json(json!([{"id": existing, "add": outside}])),
+            &get_objects(""),
+        )
+        .await;
+        expect_422(
+            TestRequest::patch().set_json(json!([{"type": "Region"}])),
+            &get_objects(""),
+        )
+        .await;
+        expect_404(
+            TestRequest::patch().set_json(json!([{"id": "21-doesnotexists", "type": "Region"}])),
+            &get_objects(""),
+        )
+        .await;
+    }
+
+    #[actix_web::test]
+    async fn patch_read_back() {
+        let existing = SPATIAL_OBJECT.trim_start_matches('/');
+        let shapes = json!([{"space": "std", "shapes": [{"points": [[0.5, 0.5, 0.5]]}]}]);
+        let patch = |patch| TestRequest::patch().set_json(json!([patch]));
+        let add = || patch(json!({"id": existing, "add": shapes}));
+        let remove = || patch(json!({"id": existing, "remove": shapes}));
+        let object = get_objects(SPATIAL_OBJECT);
+
+        let bodies = expect_sequence(vec![
+            (TestRequest::get(), object.clone(), 200),
+            (add(), get_objects(""), 200),
+            (TestRequest::get(), object.clone(), 200),
+            (remove(), get_objects(""), 200),
+            (TestRequest::get(), object.clone(), 200),
+            (remove(), get_objects(""), 200),
+        ])
+        .await;
+
+        let count = |body: &[u8]| serde_json::from_slice::<usize>(body).unwrap();
+        let points = |body: &[u8]| {
+            let objects = serde_json::from_slice::<serde_json::Value>(body).unwrap();
+            objects[0]["volumes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .flat_map(|volume| volume["shapes"].as_array().unwrap())
+                .map(|shape| shape["points"].as_array().map_or(0, Vec::len))
+                .sum::<usize>()
+        };
+
+        assert_eq!(count(&bodies[1]), 1);
+        assert_eq!(points(&bodies[2]), points(&bodies[0]) + 1);
+        assert_eq!(count(&bodies[3]), 1);
+        assert_eq!(points(&bodies[4]), points(&bodies[0]));
+        // Nothing left to remove.
+        assert_eq!(count(&bodies[5]), 0);
+    }
+
     #[actix_web::test]
     async fn delete() {
         json::expect_200(TestRequest::delete(), &get_objects(""), "".to_string()).await;
@@ -169,6 +978,21 @@ mod routing {
         expect_400(TestRequest::delete(), &get_objects("")).await;
     }
 
+    #[actix_web::test]
+    async fn delete_objects() {
+        let invalid_core = format!("{}{}", get_core(INVALID_CORE), "/spatial_objects");
+        let existing = SPATIAL_OBJECT.trim_start_matches('/');
+
+        expect_200(TestRequest::delete().set_json(json!([existing])), &get_objects("")).await;
+        expect_200(TestRequest::delete().set_json(json!([])), &get_objects("")).await;
+        expect_404(
+            TestRequest::delete().set_json(json!([existing, "21-doesnotexists"])),
+            &get_objects(""),
+        )
+        .await;
+        expect_404(TestRequest::delete().set_json(json!([existing])), &invalid_core).await;
+    }
+
     #[actix_web::test]
     async fn get() {
         expect_405(TestRequest::get(), &get_objects("")).await;
@@ -1,24 +1,804 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use mercator_db::space;
+use mercator_db::storage::model;
+use mercator_db::storage::model::v2::from_spaces_by_properties;
+use mercator_db::storage::model::v2::SpatialObject;
+use mercator_db::storage::model::v2::Volume;
+use mercator_db::Core;
 use mercator_db::CoreQueryParameters;
 use mercator_db::DataBase;
+use mercator_db::IterObjects;
+use mercator_db::IterObjectsBySpaces;
 use mercator_parser::Bag;
 use mercator_parser::Executor;
 use mercator_parser::FiltersParser;
 use mercator_parser::Projection;
 use mercator_parser::QueryParser;
 use mercator_parser::Validator;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use rayon::ThreadPoolBuilder;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::datasets::Dataset;
+
+/// Step of the processing of a query.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Parse,
+    TypeCheck,
+    Execution,
+}
+
+/// Failure to process a query.
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryError {
+    stage: Stage,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<(usize, usize)>, // Span in the query string, for parse errors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    core: Option<String>, // Core being queried, for execution errors
+}
+
+impl QueryError {
+    pub fn parse(message: String) -> Self {
+        QueryError {
+            stage: Stage::Parse,
+            position: location(&message),
+            message,
+            core: None,
+        }
+    }
+
+    pub fn type_check(message: String) -> Self {
+        QueryError {
+            stage: Stage::TypeCheck,
+            message,
+            position: None,
+            core: None,
+        }
+    }
+
+    pub fn execution(core: &str, message: String) -> Self {
+        QueryError {
+            stage: Stage::Execution,
+            message,
+            position: None,
+            core: Some(core.to_string()),
+        }
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    pub fn message(&self) -> &String {
+        &self.message
+    }
+
+    pub fn position(&self) -> &Option<(usize, usize)> {
+        &self.position
+    }
+
+    pub fn core(&self) -> &Option<String> {
+        &self.core
+    }
+}
+
+/// Time spent in each stage of the processing of a query.
+#[derive(Clone, Debug, Default)]
+pub struct Timings {
+    parse: f64,      // Milliseconds
+    type_check: f64, // Milliseconds
+    execution: f64,  // Milliseconds
+}
+
+impl Timings {
+    /// Add the time elapsed since `start` to the execution time.
+    pub fn add_execution(&mut self, start: Instant) {
+        self.execution += milliseconds(start);
+    }
+
+    /// Format the timings as the value of a `Server-Timing` HTTP header.
+    pub fn to_header(&self) -> String {
+        format!(
+            "parse;dur={:.3}, type_check;dur={:.3}, execution;dur={:.3}",
+            self.parse, self.type_check, self.execution
+        )
+    }
+}
+
+fn milliseconds(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+/// Server-wide limit on the execution time of queries.
+///
+/// It is set by `MERCATOR_QUERY_TIMEOUT`, in seconds, 0 meaning no limit.
+fn query_timeout() -> Option<Duration> {
+    static TIMEOUT: OnceLock<Option<Duration>> = OnceLock::new();
+
+    *TIMEOUT.get_or_init(|| {
+        let seconds = std::env::var("MERCATOR_QUERY_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        if seconds > 0 {
+            Some(Duration::from_secs(seconds))
+        } else {
+            None
+        }
+    })
+}
+
+/// Cooperative cancellation of a query, once its deadline is reached or on
+/// request, for example when the client disconnects.
+///
+/// Result sets are lazily computed, so guarded iterators simply end early
+/// once cancelled. It is up to the caller to report the results as
+/// incomplete, when they were actually interrupted.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation {
+    deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+    interrupted: Arc<AtomicBool>, // Some work was left undone
+}
+
+impl Cancellation {
+    /// Start the clock for a query, given the `timeout` requested by the
+    /// client, capped by the server-wide one.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        let timeout = match (timeout, query_timeout()) {
+            (Some(requested), Some(limit)) => Some(requested.min(limit)),
+            (requested, limit) => requested.or(limit),
+        };
+
+        Cancellation {
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Whether the next piece of work may proceed. Once cancelled, it is
+    /// skipped and the results are flagged as interrupted.
+    pub fn proceed(&self) -> bool {
+        if self.is_cancelled() {
+            self.interrupted.store(true, Ordering::Relaxed);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Whether some work was skipped because of the cancellation, so the
+    /// results are incomplete. Reaching the deadline after the results are
+    /// complete does not count.
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Cancel the query when the returned value is dropped, for example
+    /// along with the handler of a request whose client went away.
+    pub fn on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+
+    /// Stop the iterators of `objects` once cancelled.
+    pub fn guard<'a>(&self, objects: IterObjectsBySpaces<'a>) -> IterObjectsBySpaces<'a> {
+        objects
+            .into_iter()
+            .map(|(space, objects)| {
+                let cancellation = self.clone();
+                let objects: IterObjects =
+                    Box::new(objects.take_while(move |_| cancellation.proceed()));
+                (space, objects)
+            })
+            .collect()
+    }
+}
+
+/// Cancel a query when dropped.
+#[derive(Debug)]
+pub struct CancelOnDrop(Cancellation);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Server-wide limits on the number of objects and of positions of a
+/// response.
+///
+/// They are set by `MERCATOR_MAX_OBJECTS` and `MERCATOR_MAX_POSITIONS`, 0
+/// meaning no limit.
+fn result_limits() -> (Option<usize>, Option<usize>) {
+    static LIMITS: OnceLock<(Option<usize>, Option<usize>)> = OnceLock::new();
+
+    *LIMITS.get_or_init(|| {
+        let limit = |name| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|&v| v > 0)
+        };
+
+        (limit("MERCATOR_MAX_OBJECTS"), limit("MERCATOR_MAX_POSITIONS"))
+    })
+}
+
+/// Bound on the size of a buffered response.
+///
+/// Guarded iterators end once the maximum number of positions has been
+/// consumed, over all of them, and lists of objects are cut to the maximum
+/// number of objects. It is up to the caller to report the results as
+/// truncated.
+#[derive(Clone, Debug, Default)]
+pub struct Truncation {
+    max_objects: Option<usize>,
+    max_positions: Option<usize>,
+    positions: Arc<AtomicUsize>, // Consumed so far by the guarded iterators
+    truncated: Arc<AtomicBool>,
+}
+
+impl Truncation {
+    pub fn new() -> Self {
+        let (max_objects, max_positions) = result_limits();
+
+        Truncation::with_limits(max_objects, max_positions)
+    }
+
+    fn with_limits(max_objects: Option<usize>, max_positions: Option<usize>) -> Self {
+        Truncation {
+            max_objects,
+            max_positions,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    fn truncate(&self) {
+        self.truncated.store(true, Ordering::Relaxed);
+    }
+
+    /// Stop the iterators of `objects` once the maximum number of positions
+    /// is reached.
+    pub fn guard<'a>(&self, objects: IterObjectsBySpaces<'a>) -> IterObjectsBySpaces<'a> {
+        let max = match self.max_positions {
+            None => return objects,
+            Some(max) => max,
+        };
+
+        objects
+            .into_iter()
+            .map(|(space, objects)| {
+                let truncation = self.clone();
+                let objects: IterObjects = Box::new(objects.take_while(move |_| {
+                    if truncation.positions.fetch_add(1, Ordering::Relaxed) < max {
+                        true
+                    } else {
+                        truncation.truncate();
+                        false
+                    }
+                }));
+                (space, objects)
+            })
+            .collect()
+    }
+
+    /// Take the leading `items` whose positions, as counted by `positions`,
+    /// fit within the maximum number of positions. The first item is always
+    /// taken, so that pages make progress.
+    ///
+    /// Returns the items taken, and whether items were left out.
+    pub fn fit<I, F>(&self, items: I, positions: F) -> (Vec<I::Item>, bool)
+    where
+        I: Iterator,
+        F: Fn(&I::Item) -> usize,
+    {
+        let max = self.max_positions.unwrap_or(usize::MAX);
+
+        let mut total = 0usize;
+        let mut taken = vec![];
+        for item in items {
+            total = total.saturating_add(positions(&item));
+            if total > max && !taken.is_empty() {
+                self.truncate();
+                return (taken, true);
+            }
+            taken.push(item);
+        }
+
+        (taken, false)
+    }
+
+    /// Keep at most the maximum number of objects of `items`.
+    pub fn limit<I>(&self, items: I) -> impl Iterator<Item = I::Item>
+    where
+        I: Iterator,
+    {
+        let max = self.max_objects.unwrap_or(usize::MAX);
+        let truncation = self.clone();
+
+        items
+            .enumerate()
+            .take_while(move |(i, _)| {
+                if *i < max {
+                    true
+                } else {
+                    truncation.truncate();
+                    false
+                }
+            })
+            .map(|(_, item)| item)
+    }
+}
+
+/// Thread pool used to execute queries, distinct from the HTTP workers.
+///
+/// Its size is set by `MERCATOR_QUERY_THREADS`, 0 meaning one thread per
+/// logical CPU.
+fn query_pool() -> &'static ThreadPool {
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+    POOL.get_or_init(|| {
+        let threads = std::env::var("MERCATOR_QUERY_THREADS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("mercator-query-{}", i))
+            .build()
+            .unwrap_or_else(|e| panic!("Can't create the query thread pool: {}", e))
+    })
+}
+
+/// Extract the location from a parser error message, which ends with either
+/// `at <offset>` or `at <start>:<end>`.
+fn location(message: &str) -> Option<(usize, usize)> {
+    let location = &message[message.rfind(" at ")? + 4..];
+    let location = location
+        .split(|c: char| !c.is_ascii_digit() && c != ':')
+        .next()?;
+
+    let mut offsets = location.split(':').map(str::parse::<usize>);
+    match (offsets.next(), offsets.next()) {
+        (Some(Ok(start)), Some(Ok(end))) => Some((start, end)),
+        (Some(Ok(start)), None) => Some((start, start)),
+        _ => None,
+    }
+}
+
+/// Status of the last index rebuild of a core.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum IndexStatus {
+    Idle,
+    Running { started: u64 },
+    Done { started: u64, duration: f64 },
+    Failed { started: u64, reason: String },
+}
+
+/// Content of a core in one reference space.
+#[derive(Clone, Debug, Serialize)]
+pub struct SpaceSummary {
+    space: String,
+    scales: Vec<Vec<u32>>, // Empty when selected automatically
+    objects: usize,
+    positions: usize,
+    bounding_box: (Vec<f64>, Vec<f64>),
+}
+
+/// Shapes, as serialized by the model.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Shapes {
+    #[serde(rename = "points")]
+    Points(Vec<Vec<f64>>),
+    #[serde(rename = "boundingboxes")]
+    BoundingBoxes(Vec<(Vec<f64>, Vec<f64>)>),
+    #[serde(rename = "hyperspheres")]
+    HyperSpheres(Vec<(Vec<f64>, f64)>),
+}
+
+impl Shapes {
+    /// Decode `shape`, as stored in a volume.
+    pub fn from_model<S: Serialize>(shape: &S) -> Result<Self, String> {
+        serde_json::to_value(shape)
+            .and_then(Shapes::deserialize)
+            .map_err(|e| format!("Unsupported shape: {}", e))
+    }
+
+    /// Encode as a shape of a volume.
+    pub fn to_model<S: DeserializeOwned>(&self) -> Result<S, String> {
+        serde_json::to_value(self)
+            .and_then(serde_json::from_value)
+            .map_err(|e| format!("Unsupported shape: {}", e))
+    }
+
+    /// Whether `position` is part of these shapes, up to the precision of
+    /// `graduations`.
+    pub fn contains(&self, position: &[f64], graduations: &Graduations) -> bool {
+        match self {
+            Shapes::Points(points) => points
+                .iter()
+                .any(|point| graduations.within(position, point, point)),
+            Shapes::BoundingBoxes(boxes) => boxes
+                .iter()
+                .any(|(low, high)| graduations.within(position, low, high)),
+            Shapes::HyperSpheres(spheres) => spheres.iter().any(|(center, radius)| {
+                center.len() == position.len()
+                    && center
+                        .iter()
+                        .zip(position)
+                        .map(|(c, p)| (c - p) * (c - p))
+                        .sum::<f64>()
+                        .sqrt()
+                        <= radius + graduations.precision()
+            }),
+        }
+    }
+}
+
+/// Range and step of each axis of a reference space.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Graduations {
+    axes: Vec<(f64, f64, f64)>, // Minimum, maximum, step
+}
+
+impl Graduations {
+    pub fn new(space: &space::Space) -> Self {
+        let axes = model::Space::from(space)
+            .axes
+            .iter()
+            .map(|axis| {
+                let graduation = &axis.graduation;
+                let step = match graduation.steps {
+                    0 => 0.0,
+                    steps => (graduation.maximum - graduation.minimum) / steps as f64,
+                };
+
+                (graduation.minimum, graduation.maximum, step)
+            })
+            .collect();
+
+        Graduations { axes }
+    }
+
+    /// Lowest and highest graduations of the axes.
+    pub fn bounds(&self) -> (Vec<f64>, Vec<f64>) {
+        self.axes.iter().map(|&(low, high, _)| (low, high)).unzip()
+    }
+
+    /// Check every position of `shapes` lies within the axes.
+    pub fn validate(&self, shapes: &Shapes) -> Result<(), String> {
+        match shapes {
+            Shapes::Points(points) => points.iter().try_for_each(|p| self.check(p, 0.0)),
+            Shapes::BoundingBoxes(boxes) => boxes
+                .iter()
+                .try_for_each(|(low, high)| self.check(low, 0.0).and(self.check(high, 0.0))),
+            Shapes::HyperSpheres(spheres) => {
+                spheres.iter().try_for_each(|(center, radius)| {
+                    if !radius.is_finite() || *radius < 0.0 {
+                        return Err(format!("Invalid radius {}", radius));
+                    }
+                    self.check(center, *radius)
+                })
+            }
+        }
+    }
+
+    // Whether `position` lies between `low` and `high`, extended by half a
+    // step along every axis.
+    fn within(&self, position: &[f64], low: &[f64], high: &[f64]) -> bool {
+        position.len() == self.axes.len()
+            && low.len() == self.axes.len()
+            && high.len() == self.axes.len()
+            && self
+                .axes
+                .iter()
+                .enumerate()
+                .all(|(k, (_, _, step))| {
+                    position[k] >= low[k] - step / 2.0 && position[k] <= high[k] + step / 2.0
+                })
+    }
+
+    // Largest distance between a position and its graduation.
+    fn precision(&self) -> f64 {
+        self.axes
+            .iter()
+            .map(|(_, _, step)| step * step / 4.0)
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    // Check `position`, extended by `margin` along every axis.
+    fn check(&self, position: &[f64], margin: f64) -> Result<(), String> {
+        if position.len() != self.axes.len() {
+            return Err(format!(
+                "Expected {} coordinates, got {:?}",
+                self.axes.len(),
+                position
+            ));
+        }
+
+        for (value, (minimum, maximum, _)) in position.iter().zip(&self.axes) {
+            if !value.is_finite() || value - margin < *minimum || value + margin > *maximum {
+                return Err(format!(
+                    "Coordinates {:?} out of range [{}, {}]",
+                    position, minimum, maximum
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Versions of the cores and reference spaces, so changes prepared from a
+/// snapshot are only installed over the state they were prepared from.
+#[derive(Clone, Debug, Default)]
+struct Generations {
+    // Incremented on every change, so a core or space dropped then created
+    // again never gets a previous generation back.
+    last: u64,
+    cores: HashMap<String, u64>,
+    spaces: HashMap<String, u64>,
+    // Generation of the cores when they were loaded from an index file.
+    loaded: HashMap<String, u64>,
+}
+
+impl Generations {
+    fn core(&self, name: &str) -> u64 {
+        self.cores.get(name).copied().unwrap_or(0)
+    }
+
+    fn space(&self, name: &str) -> u64 {
+        self.spaces.get(name).copied().unwrap_or(0)
+    }
+
+    fn touch_core(&mut self, name: &str) {
+        self.last += 1;
+        self.cores.insert(name.to_string(), self.last);
+    }
+
+    fn touch_space(&mut self, name: &str) {
+        self.last += 1;
+        self.spaces.insert(name.to_string(), self.last);
+    }
+
+    fn load_core(&mut self, name: &str) {
+        self.touch_core(name);
+        self.loaded.insert(name.to_string(), self.last);
+    }
+
+    // Whether the core `name` was modified since it was loaded.
+    fn is_modified(&self, name: &str) -> bool {
+        self.core(name) != self.loaded.get(name).copied().unwrap_or(0)
+    }
+}
+
+/// Snapshot of a core, used to rebuild its index without holding the lock
+/// on the shared state.
+pub struct IndexJob {
+    name: String,
+    version: String,
+    spaces: Vec<space::Space>,
+    objects: Vec<SpatialObject>,
+    scales: Option<Vec<Vec<u32>>>,
+    generations: Generations,
+}
+
+impl IndexJob {
+    /// Insert `objects`, replacing the objects with the same id.
+    ///
+    /// Returns the number of objects inserted or replaced.
+    pub fn insert(&mut self, objects: Vec<SpatialObject>) -> usize {
+        let mut positions = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(idx, object)| (object.properties.id.clone(), idx))
+            .collect::<HashMap<_, _>>();
+
+        let count = objects.len();
+        for object in objects {
+            match positions.get(&object.properties.id) {
+                Some(&idx) => self.objects[idx] = object,
+                None => {
+                    positions.insert(object.properties.id.clone(), self.objects.len());
+                    self.objects.push(object);
+                }
+            }
+        }
+
+        count
+    }
+
+    /// Apply `update` to every object.
+    ///
+    /// `update` returns whether the object was modified. Returns the number
+    /// of objects modified.
+    pub fn update<F>(&mut self, mut update: F) -> usize
+    where
+        F: FnMut(&mut SpatialObject) -> bool,
+    {
+        self.objects
+            .iter_mut()
+            .map(|object| update(object))
+            .filter(|modified| *modified)
+            .count()
+    }
+
+    /// Retrieve the ids from `ids` which are not part of the snapshot.
+    pub fn unknown_ids<'i>(&self, ids: &'i [String]) -> Vec<&'i String> {
+        let known = self
+            .objects
+            .iter()
+            .map(|object| &object.properties.id)
+            .collect::<HashSet<_>>();
+
+        ids.iter().filter(|id| !known.contains(id)).collect()
+    }
+
+    /// Remove the objects `ids`.
+    ///
+    /// Returns the number of objects removed.
+    pub fn remove(&mut self, ids: &[String]) -> usize {
+        let ids = ids.iter().collect::<HashSet<_>>();
+
+        let count = self.objects.len();
+        self.objects.retain(|object| !ids.contains(&object.properties.id));
+
+        count - self.objects.len()
+    }
+
+    // Whether neither the core, nor the reference spaces its objects use,
+    // changed since the snapshot was taken.
+    fn is_current(&self, generations: &Generations) -> bool {
+        generations.core(&self.name) == self.generations.core(&self.name)
+            && self
+                .objects
+                .iter()
+                .flat_map(|object| object.volumes.iter())
+                .all(|v| generations.space(&v.space) == self.generations.space(&v.space))
+    }
+
+    pub fn build(&self) -> Core {
+        info_time!("Building index of core {}", self.name);
+        model::build_index(
+            &self.name,
+            &self.version,
+            &self.spaces,
+            &self.objects,
+            self.scales.clone(),
+            None,
+        )
+    }
+}
+
+/// Snapshot of the cores with objects in a reference space, used to
+/// replace its definition without holding the lock on the shared state.
+pub struct SpaceJob {
+    space: space::Space,
+    spaces: Vec<space::Space>,
+    // Name, version, objects and scales of the cores using the space.
+    cores: Vec<(String, String, Vec<SpatialObject>, Option<Vec<Vec<u32>>>)>,
+    // The objects stored in the space have to be checked against the new
+    // definition.
+    changed: bool,
+    generations: Generations,
+}
+
+impl SpaceJob {
+    /// Check the objects stored in the reference space fit within its new
+    /// definition.
+    pub fn check(&self) -> Result<(), String> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let name = self.space.name();
+        let graduations = Graduations::new(&self.space);
+
+        for (core, _, objects, _) in &self.cores {
+            for object in objects {
+                for volume in object.volumes.iter().filter(|v| &v.space == name) {
+                    for shape in &volume.shapes {
+                        graduations
+                            .validate(&Shapes::from_model(shape)?)
+                            .map_err(|e| {
+                                format!(
+                                    "Object '{}' of core '{}' does not fit: {}",
+                                    object.properties.id, core, e
+                                )
+                            })?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn build(&self) -> Vec<Core> {
+        self.cores
+            .iter()
+            .map(|(name, version, objects, scales)| {
+                info_time!("Building index of core {}", name);
+                model::build_index(name, version, &self.spaces, objects, scales.clone(), None)
+            })
+            .collect()
+    }
+
+    // Whether neither the reference space, nor any core, changed since the
+    // snapshot was taken. Every core is compared, as one of them may have
+    // started to use the space meanwhile.
+    fn is_current(&self, generations: &Generations) -> bool {
+        let name = self.space.name();
+
+        generations.space(name) == self.generations.space(name)
+            && generations.cores == self.generations.cores
+    }
+}
 
 pub struct SharedState {
-    db: DataBase,
-    query_parser: QueryParser,
-    filter_parser: FiltersParser,
+    // Shared with the snapshots taken by long running readers.
+    db: Arc<DataBase>,
+    query_parser: Arc<QueryParser>,
+    filter_parser: Arc<FiltersParser>,
+    datasets: Vec<Dataset>,
+    // Scales requested when a core was (re)created through the API.
+    scales: HashMap<String, Option<Vec<Vec<u32>>>>,
+    indexing: HashMap<String, (IndexStatus, Instant)>,
+    generations: Generations,
+    // Per core summaries, valid for the recorded generation of the core
+    // only.
+    summaries: Mutex<HashMap<String, (u64, Vec<SpaceSummary>)>>,
 }
 
 impl SharedState {
-    pub fn new(db: DataBase) -> Self {
+    pub fn new(db: DataBase, datasets: Vec<Dataset>) -> Self {
         SharedState {
-            db,
-            query_parser: QueryParser::new(),
-            filter_parser: FiltersParser::new(),
+            db: Arc::new(db),
+            datasets,
+            query_parser: Arc::new(QueryParser::new()),
+            filter_parser: Arc::new(FiltersParser::new()),
+            scales: HashMap::new(),
+            indexing: HashMap::new(),
+            generations: Generations::default(),
+            summaries: Mutex::new(HashMap::new()),
         }
     }
 
@@ -26,6 +806,68 @@ impl SharedState {
         &self.db
     }
 
+    /// Copy of the state, sharing the current database, for readers which
+    /// can't hold the lock of the shared state for their whole duration.
+    ///
+    /// Modifications made afterwards are not visible through the snapshot.
+    pub fn snapshot(&self) -> SharedState {
+        SharedState {
+            db: self.db.clone(),
+            query_parser: self.query_parser.clone(),
+            filter_parser: self.filter_parser.clone(),
+            datasets: self.datasets.clone(),
+            scales: self.scales.clone(),
+            indexing: self.indexing.clone(),
+            generations: self.generations.clone(),
+            summaries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Index files the database was loaded from.
+    pub fn datasets(&self) -> &Vec<Dataset> {
+        &self.datasets
+    }
+
+    /// Replace the database after reloading the index files, which are now
+    /// `datasets`. The cores previously loaded from the files which changed
+    /// are listed in `stale`.
+    ///
+    /// The changes made through the API to the stale cores are lost.
+    pub fn replace(&mut self, db: DataBase, datasets: Vec<Dataset>, stale: &HashSet<String>) {
+        for core in stale.iter().filter(|core| self.generations.is_modified(core)) {
+            warn!(
+                "Core {} reloaded from its index file, its changes through the API are lost",
+                core
+            );
+        }
+
+        // Only the stale cores, and the cores and spaces added, changed.
+        let cores = db
+            .core_keys()
+            .iter()
+            .filter(|id| self.db.core(id).is_err())
+            .chain(stale.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        let spaces = db
+            .space_keys()
+            .iter()
+            .filter(|id| self.db.space(id).is_err())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        self.db = Arc::new(db);
+        self.datasets = datasets;
+        self.scales.retain(|name, _| !stale.contains(name));
+
+        for core in cores {
+            self.generations.load_core(&core);
+        }
+        for space in spaces {
+            self.generations.touch_space(&space);
+        }
+    }
+
     pub fn filter_parser(&self) -> &FiltersParser {
         &self.filter_parser
     }
@@ -34,15 +876,22 @@ impl SharedState {
         &self.query_parser
     }
 
+    /// Execute `tree` on `core`. The results are cut short, possibly before
+    /// the execution starts, when `cancellation` triggers.
     pub fn execute<'e, T>(
         &'e self,
         tree: &'e T, //&'e Bag,
         core: &'e str,
         parameters: &'e CoreQueryParameters<'e>,
+        cancellation: &Cancellation,
     ) -> mercator_db::ResultSet<'e>
     where
         T: Executor<'e, ResultSet = mercator_db::ResultSet<'e>>,
     {
+        if !cancellation.proceed() {
+            return Ok(vec![]);
+        }
+
         // Execute filter.
         let execution = {
             info_time!("Execution");
@@ -56,11 +905,31 @@ impl SharedState {
                 debug!("Execution failed: \n{:?}", e);
                 Err(e)
             }
-            results @ Ok(_) => results,
+            Ok(objects) => Ok(cancellation.guard(objects)),
         }
     }
 
-    pub fn filter<'q>(&'q self, filter: &'q str) -> Result<Bag, String> {
+    /// Call `f` on every core of the database for which `visible` holds,
+    /// concurrently on the query thread pool, and return the results paired
+    /// with the core ids, in the order of `core_keys()`.
+    pub fn for_each_core<V, F, R>(&self, visible: V, f: F) -> Vec<(&String, R)>
+    where
+        V: Fn(&str) -> bool + Send + Sync,
+        F: Fn(&String) -> R + Send + Sync,
+        R: Send,
+    {
+        let cores = self.db.core_keys();
+
+        query_pool().install(|| {
+            cores
+                .par_iter()
+                .filter(|core| visible(core))
+                .map(|core| (core, f(core)))
+                .collect()
+        })
+    }
+
+    pub fn filter<'q>(&'q self, filter: &'q str) -> Result<Bag, QueryError> {
         let parser = self.filter_parser();
         let parse;
 
@@ -73,13 +942,13 @@ impl SharedState {
         match parse {
             Err(e) => {
                 debug!("Parsing failed: \n{:?}", e);
-                Err(format!("{}", e))
+                Err(QueryError::parse(format!("{}", e)))
             }
             Ok(tree) => {
                 // Check type coherence & validate tree
                 {
                     debug_time!("Type check");
-                    let _ = tree.validate()?;
+                    let _ = tree.validate().map_err(QueryError::type_check)?;
                 }
 
                 Ok(tree)
@@ -87,30 +956,511 @@ impl SharedState {
         }
     }
 
-    pub fn query(&self, query: &str) -> Result<Projection, String> {
+    pub fn query(&self, query: &str, timings: &mut Timings) -> Result<Projection, QueryError> {
         let parser = self.query_parser();
         let parse;
 
         // Parse Input
         {
             debug_time!("Parsing");
+            let start = Instant::now();
             parse = parser.parse(query);
+            timings.parse = milliseconds(start);
         }
         match parse {
             Err(e) => {
                 debug!("Parsing failed: \n{:?}", e);
-                Err(e.to_string())
+                Err(QueryError::parse(e.to_string()))
             }
-            Ok(None) => Err("Query is empty!".to_string()),
+            Ok(None) => Err(QueryError::parse("Query is empty!".to_string())),
             Ok(Some(tree)) => {
                 // Check type coherence & validate tree
                 {
                     debug_time!("Type check");
-                    let _ = tree.validate()?;
+                    let start = Instant::now();
+                    let validation = tree.validate();
+                    timings.type_check = milliseconds(start);
+                    let _ = validation.map_err(QueryError::type_check)?;
                 }
 
                 Ok(tree)
             }
         }
     }
+
+    /// Retrieve, at full resolution, all the objects stored in `core`.
+    pub fn objects(&self, core: &str) -> Result<Vec<SpatialObject>, String> {
+        let db = self.db();
+        let core = db.core(core)?;
+
+        let parameters = CoreQueryParameters {
+            db,
+            output_space: None,
+            // Enforce highest resolution index.
+            threshold_volume: None,
+            view_port: &None,
+            resolution: &Some(vec![0]),
+        };
+
+        // An object missing from the result would be dropped by the caller
+        // when it installs the objects back, so fail instead.
+        let objects_by_spaces = core
+            .keys()
+            .iter()
+            .map(|property| {
+                core.get_by_id(&parameters, property.id())
+                    .map(|positions_by_spaces| (property, positions_by_spaces))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(from_spaces_by_properties(Box::new(objects_by_spaces.into_iter())).collect())
+    }
+
+    /// Scales requested for the core `name`, None when they were selected
+    /// automatically, or when the core was loaded from an index file.
+    pub fn scales(&self, name: &str) -> Option<Vec<Vec<u32>>> {
+        self.scales.get(name).cloned().flatten()
+    }
+
+    /// Summarize, per reference space, the content of `core`.
+    ///
+    /// This requires a full scan of the core, so the result is cached until
+    /// the core changes.
+    pub fn summary(&self, core: &str) -> Result<Vec<SpaceSummary>, String> {
+        let summaries = || {
+            self.summaries
+                .lock()
+                .unwrap_or_else(|e| panic!("Can't acquire lock of the summaries: {}", e))
+        };
+
+        // The lock is not held during the scan, so cores are summarized
+        // concurrently. The same core may then be scanned twice.
+        let generation = self.generations.core(core);
+        if let Some((cached, summary)) = summaries().get(core) {
+            if *cached == generation {
+                return Ok(summary.clone());
+            }
+        }
+
+        let db = self.db();
+        let parameters = CoreQueryParameters {
+            db,
+            output_space: None,
+            // Enforce highest resolution index.
+            threshold_volume: None,
+            view_port: &None,
+            resolution: &Some(vec![0]),
+        };
+        // FIXME: mercator_db does not expose the scales an index was built
+        //        with, so only the ones requested through the API, used
+        //        for every space of the core, are known. They are reported
+        //        empty for cores loaded from index files.
+        let scales = self.scales(core).unwrap_or_default();
+
+        let mut by_spaces = HashMap::new();
+        let handle = db.core(core)?;
+        for properties in handle.keys() {
+            for (space, positions) in handle.get_by_id(&parameters, properties.id())? {
+                let summary = by_spaces
+                    .entry(space.to_string())
+                    .or_insert_with(|| SpaceSummary {
+                        space: space.to_string(),
+                        scales: scales.clone(),
+                        objects: 0,
+                        positions: 0,
+                        bounding_box: (vec![], vec![]),
+                    });
+
+                summary.objects += 1;
+                for position in positions {
+                    let position: Vec<f64> = position.into();
+                    let (low, high) = &mut summary.bounding_box;
+
+                    if summary.positions == 0 {
+                        *low = position.clone();
+                        *high = position;
+                    } else {
+                        for (k, v) in position.into_iter().enumerate() {
+                            low[k] = low[k].min(v);
+                            high[k] = high[k].max(v);
+                        }
+                    }
+                    summary.positions += 1;
+                }
+            }
+        }
+
+        let mut summary = by_spaces.drain().map(|(_, v)| v).collect::<Vec<_>>();
+        summary.sort_by(|a, b| a.space.cmp(&b.space));
+
+        summaries().insert(core.to_string(), (generation, summary.clone()));
+
+        Ok(summary)
+    }
+
+    /// Graduations of every reference space, by name.
+    pub fn graduations(&self) -> HashMap<String, Graduations> {
+        self.db
+            .space_keys()
+            .iter()
+            .filter_map(|id| match self.db.space(id) {
+                Err(_) => None,
+                Ok(space) => Some((id.clone(), Graduations::new(space))),
+            })
+            .collect()
+    }
+
+    /// Check the reference spaces used by `volumes` are all known, and
+    /// their shapes fit within these spaces.
+    pub fn validate<'v, I>(&self, volumes: I) -> Result<(), String>
+    where
+        I: IntoIterator<Item = &'v Volume>,
+    {
+        let mut graduations = HashMap::new();
+
+        for volume in volumes {
+            let space = match self.db.space(&volume.space) {
+                Err(_) => return Err(format!("Invalid reference space id '{}'", volume.space)),
+                Ok(space) => space,
+            };
+            let graduations = graduations
+                .entry(&volume.space)
+                .or_insert_with(|| Graduations::new(space));
+
+            for shape in &volume.shapes {
+                graduations
+                    .validate(&Shapes::from_model(shape)?)
+                    .map_err(|e| format!("Invalid shape in '{}': {}", volume.space, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unload the core `name` from the database, and from the description
+    /// of the index file it was loaded from.
+    pub fn drop_core(&mut self, name: &str) -> Result<(), String> {
+        let _ = self.db.core(name)?;
+
+        let spaces = self.spaces()?;
+        let cores = self.cores(|id| id != name)?;
+        self.db = Arc::new(DataBase::new(spaces, cores));
+        self.scales.remove(name);
+        self.generations.touch_core(name);
+
+        for dataset in self.datasets.iter_mut() {
+            dataset.remove_core(name);
+        }
+
+        Ok(())
+    }
+
+    /// Forget the index file `path`, once it has been removed.
+    pub fn drop_dataset(&mut self, path: &str) {
+        self.datasets.retain(|dataset| dataset.path() != path);
+    }
+
+    /// Status of the last index rebuild of the core `name`.
+    pub fn index_status(&self, name: &str) -> IndexStatus {
+        match self.indexing.get(name) {
+            None => IndexStatus::Idle,
+            Some((status, _)) => status.clone(),
+        }
+    }
+
+    /// Mark the index of the core `name` as being rebuilt.
+    ///
+    /// Returns false if a rebuild is already running for that core.
+    pub fn start_indexing(&mut self, name: &str) -> bool {
+        if let IndexStatus::Running { .. } = self.index_status(name) {
+            return false;
+        }
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        self.indexing.insert(
+            name.to_string(),
+            (IndexStatus::Running { started }, Instant::now()),
+        );
+
+        true
+    }
+
+    /// Snapshot the core `name`, in order to rebuild its index with
+    /// `scales`, or with the scales previously requested if None.
+    pub fn index_job(
+        &self,
+        name: &str,
+        scales: Option<Option<Vec<Vec<u32>>>>,
+    ) -> Result<IndexJob, String> {
+        let scales = match scales {
+            Some(scales) => scales,
+            None => self.scales.get(name).cloned().flatten(),
+        };
+
+        Ok(IndexJob {
+            name: name.to_string(),
+            version: self.db.core(name)?.version().clone(),
+            spaces: self.spaces()?,
+            objects: self.objects(name)?,
+            scales,
+            generations: self.generations.clone(),
+        })
+    }
+
+    /// Snapshot the core `name`, in order to create it with `version` and
+    /// `scales`, or to update these while keeping its objects.
+    pub fn create_job(
+        &self,
+        name: &str,
+        version: &str,
+        scales: Option<Vec<Vec<u32>>>,
+    ) -> Result<IndexJob, String> {
+        let objects = match self.db.core(name) {
+            Err(_) => vec![],
+            Ok(_) => self.objects(name)?,
+        };
+
+        Ok(IndexJob {
+            name: name.to_string(),
+            version: version.to_string(),
+            spaces: self.spaces()?,
+            objects,
+            scales,
+            generations: self.generations.clone(),
+        })
+    }
+
+    /// Record the outcome of the index rebuild of the core `name`, and
+    /// install the new `core` if the core did not change meanwhile.
+    pub fn finish_indexing(&mut self, name: &str, result: Result<(IndexJob, Core), String>) {
+        let (started, start) = match self.indexing.get(name) {
+            Some((IndexStatus::Running { started }, start)) => (*started, *start),
+            _ => return,
+        };
+
+        let result = result.and_then(|(job, core)| match self.install(job, core)? {
+            true => Ok(()),
+            false => Err("Core modified during the rebuild, please retry".to_string()),
+        });
+
+        let status = match result {
+            Ok(()) => IndexStatus::Done {
+                started,
+                duration: start.elapsed().as_secs_f64(),
+            },
+            Err(reason) => {
+                warn!("Rebuilding index of core {} failed: {}", name, reason);
+                IndexStatus::Failed { started, reason }
+            }
+        };
+
+        self.indexing.insert(name.to_string(), (status, start));
+    }
+
+    /// Install `core`, built from `job`, unless the core or the reference
+    /// spaces it uses were modified since the snapshot was taken.
+    ///
+    /// Returns false when the snapshot is stale.
+    pub fn install(&mut self, job: IndexJob, core: Core) -> Result<bool, String> {
+        if !job.is_current(&self.generations) {
+            return Ok(false);
+        }
+
+        // Other reference spaces may have changed meanwhile, keep them.
+        let spaces = self.spaces()?;
+        self.scales.insert(job.name, job.scales);
+        self.swap(spaces, vec![core])?;
+
+        Ok(true)
+    }
+
+    fn spaces(&self) -> Result<Vec<space::Space>, String> {
+        self.db
+            .space_keys()
+            .iter()
+            .map(|id| self.db.space(id).map(Clone::clone))
+            .collect()
+    }
+
+    fn cores<P>(&self, predicate: P) -> Result<Vec<Core>, String>
+    where
+        P: Fn(&str) -> bool,
+    {
+        self.db
+            .core_keys()
+            .iter()
+            .filter(|id| predicate(id))
+            .map(|id| self.db.core(id).map(Clone::clone))
+            .collect()
+    }
+
+    /// Snapshot the cores which have objects in the reference space
+    /// replaced by `space`, in order to rebuild their index with its new
+    /// definition.
+    pub fn space_job(&self, space: space::Space) -> Result<SpaceJob, String> {
+        let name = space.name().clone();
+
+        // Retrieve the objects while the previous definition is in place.
+        let mut cores = vec![];
+        let changed = match self.db.space(&name) {
+            // No objects can reference a new space.
+            Err(_) => false,
+            Ok(current) => {
+                for core in self.db.core_keys() {
+                    let objects = self.objects(core)?;
+                    if objects
+                        .iter()
+                        .any(|object| object.volumes.iter().any(|v| v.space == name))
+                    {
+                        let version = self.db.core(core)?.version().clone();
+                        cores.push((core.clone(), version, objects, self.scales(core)));
+                    }
+                }
+
+                Graduations::new(current) != Graduations::new(&space)
+            }
+        };
+
+        let mut spaces = self.spaces()?;
+        match spaces.iter_mut().find(|s| s.name() == &name) {
+            Some(s) => *s = space.clone(),
+            None => spaces.push(space.clone()),
+        }
+
+        Ok(SpaceJob {
+            space,
+            spaces,
+            cores,
+            changed,
+            generations: self.generations.clone(),
+        })
+    }
+
+    /// Create or replace the reference space of `job`, along with the
+    /// `cores` rebuilt from it, unless the space or any core were modified
+    /// since the snapshot was taken.
+    ///
+    /// Returns false when the snapshot is stale.
+    pub fn install_space(&mut self, job: SpaceJob, cores: Vec<Core>) -> Result<bool, String> {
+        if !job.is_current(&self.generations) {
+            return Ok(false);
+        }
+
+        let name = job.space.name().clone();
+
+        // Other reference spaces may have changed meanwhile, keep them.
+        let mut spaces = self.spaces()?;
+        match spaces.iter_mut().find(|s| s.name() == &name) {
+            Some(s) => *s = job.space,
+            None => spaces.push(job.space),
+        }
+
+        self.generations.touch_space(&name);
+        self.swap(spaces, cores)?;
+
+        Ok(true)
+    }
+
+    /// Retrieve the name of a core which has objects in the reference
+    /// space `name`, if any.
+    pub fn space_in_use(&self, name: &str) -> Result<Option<String>, String> {
+        let db = self.db();
+        let parameters = CoreQueryParameters {
+            db,
+            output_space: None,
+            threshold_volume: None,
+            view_port: &None,
+            resolution: &None,
+        };
+
+        for id in db.core_keys() {
+            let core = db.core(id)?;
+            for properties in core.keys() {
+                let positions_by_spaces = core.get_by_id(&parameters, properties.id())?;
+                if positions_by_spaces.iter().any(|(space, _)| *space == name) {
+                    return Ok(Some(id.clone()));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Remove the reference space `name` from the database.
+    pub fn drop_space(&mut self, name: &str) -> Result<(), String> {
+        let _ = self.db.space(name)?;
+
+        let spaces = self
+            .spaces()?
+            .into_iter()
+            .filter(|space| space.name() != name)
+            .collect();
+
+        self.generations.touch_space(name);
+        self.swap(spaces, vec![])
+    }
+
+    /// Replace the database with one made of `spaces`, the `cores` provided
+    /// and the current cores which are not replaced.
+    fn swap(&mut self, spaces: Vec<space::Space>, cores: Vec<Core>) -> Result<(), String> {
+        let replaced = cores.iter().map(|core| core.name().clone()).collect::<HashSet<_>>();
+
+        let mut kept = self.cores(|id| !replaced.contains(id))?;
+        kept.extend(cores);
+
+        // Swap the database only once the new one is complete.
+        self.db = Arc::new(DataBase::new(spaces, kept));
+        for name in &replaced {
+            self.generations.touch_core(name);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncation() {
+        let truncation = Truncation::with_limits(Some(2), None);
+        assert_eq!(truncation.limit(0..2).count(), 2);
+        assert!(!truncation.is_truncated());
+
+        assert_eq!(truncation.limit(0..3).collect::<Vec<_>>(), vec![0, 1]);
+        assert!(truncation.is_truncated());
+
+        let unlimited = Truncation::with_limits(None, None);
+        assert_eq!(unlimited.limit(0..3).count(), 3);
+        assert!(!unlimited.is_truncated());
+
+        let positions = Truncation::with_limits(None, Some(5));
+        assert_eq!(positions.fit(vec![2, 3].into_iter(), |&n| n), (vec![2, 3], false));
+        assert!(!positions.is_truncated());
+        assert_eq!(positions.fit(vec![2, 3, 1].into_iter(), |&n| n), (vec![2, 3], true));
+        assert!(positions.is_truncated());
+
+        let positions = Truncation::with_limits(None, Some(5));
+        assert_eq!(positions.fit(vec![8, 1].into_iter(), |&n| n), (vec![8], true));
+    }
+
+    #[test]
+    fn cancellation() {
+        let cancellation = Cancellation::default();
+        assert!(cancellation.proceed());
+        assert!(!cancellation.is_interrupted());
+
+        // Cancelling once the work is done leaves the results complete.
+        cancellation.cancel();
+        assert!(cancellation.is_cancelled());
+        assert!(!cancellation.is_interrupted());
+
+        assert!(!cancellation.proceed());
+        assert!(cancellation.is_interrupted());
+    }
 }